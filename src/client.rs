@@ -0,0 +1,742 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Клиент (мастер) Modbus RTU: формирование запросов, ожидание ответа,
+// проверка CRC, эха запроса и ответов-исключений
+//------------------------------------------------------------------------------
+use std::fmt;
+use std::io;
+use std::io::ErrorKind;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use byteorder::{ ByteOrder, BigEndian, LittleEndian };
+use serialport::SerialPort;
+
+use crate::server::IN_BUF_SIZE;
+use crate::server::formal::*;
+use crate::server::handler::QueryLen;
+use crate::server::identification::MEI_READ_DEVICE_ID;
+use crate::server::diagnostics::DIAG_FORCE_LISTEN_ONLY;
+use crate::server::file::{ FILE_REFERENCE_TYPE, FILE_SUB_REQUEST_LEN };
+use crate::transport::{ Transport, MIN_FRAME_GAP };
+use crate::monitor::FrameKind;
+
+pub mod poll;
+pub mod scan;
+pub mod detect;
+
+pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_millis(1000);
+// Пауза после широковещательного запроса, за которую устройства успевают его выполнить
+pub const DEFAULT_TURNAROUND_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum ClientError {
+	Io(io::Error),
+	// Ответ не получен за время ожидания
+	Timeout,
+	// Ответ с неверной контрольной суммой
+	Crc,
+	// Устройство ответило исключением
+	Exception { function: u8, code: u8 },
+	// Ответ не соответствует запросу
+	InvalidResponse(String),
+	// Запрос нельзя сформировать
+	InvalidRequest(String),
+}
+
+impl ClientError {
+	// Код исключения, если устройство ответило известным исключением
+	pub fn exception(&self) -> Option<MbExc> {
+		match self {
+			ClientError::Exception { code, .. } => num::FromPrimitive::from_u8(*code),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for ClientError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ClientError::Io(e) => write!(f, "ошибка ввода-вывода: {}", e),
+			ClientError::Timeout => write!(f, "нет ответа"),
+			ClientError::Crc => write!(f, "ошибка CRC в ответе"),
+			ClientError::Exception { function, code } => match self.exception() {
+				Some(exc) => write!(f, "функция 0x{:02X}: исключение {:?} ({})", function, exc, code),
+				None      => write!(f, "функция 0x{:02X}: исключение {}", function, code),
+			},
+			ClientError::InvalidResponse(message) => write!(f, "неверный ответ: {}", message),
+			ClientError::InvalidRequest(message) => write!(f, "неверный запрос: {}", message),
+		}
+	}
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+	fn from(e: io::Error) -> ClientError { ClientError::Io(e) }
+}
+
+fn invalid_response(message: &str) -> ClientError {
+	ClientError::InvalidResponse(message.into())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommEventCounter {
+	pub status:      u16,
+	pub event_count: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommEventLog {
+	pub status:        u16,
+	pub event_count:   u16,
+	pub message_count: u16,
+	// Последнее событие в начале
+	pub events:        Vec<u8>,
+}
+
+// Подзапрос чтения файла: length записей, начиная с record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileRecordRequest {
+	pub file:   u16,
+	pub record: u16,
+	pub length: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceIdentificationResponse {
+	pub code:         u8,
+	pub conformity:   u8,
+	pub more_follows: bool,
+	pub next_object:  u8,
+	pub objects:      Vec<(u8, Vec<u8>)>,
+}
+
+// Трассировка обмена: вид кадра, кадр RTU с CRC и, для ответа, запрос без CRC
+pub type TraceFn = Box<dyn FnMut(FrameKind, &[u8], Option<&[u8]>) + Send>;
+
+pub struct Client<T: Transport = Box<dyn SerialPort>> {
+	port:       T,
+	timeout:    Duration,
+	turnaround: Duration,
+	buf:        Vec<u8>,
+	trace:      Option<TraceFn>,
+}
+
+impl<T: Transport> Client<T> {
+	pub fn new(port: T) -> Client<T> {
+		Client {
+			port,
+			timeout:    DEFAULT_RESPONSE_TIMEOUT,
+			turnaround: DEFAULT_TURNAROUND_DELAY,
+			buf:        vec![0; IN_BUF_SIZE],
+			trace:      None,
+		}
+	}
+
+	// Вызов trace для каждого отправленного запроса и принятого ответа
+	pub fn set_trace<F>(&mut self, trace: F)
+		where F: FnMut(FrameKind, &[u8], Option<&[u8]>) + Send + 'static
+	{
+		self.trace = Some(Box::new(trace));
+	}
+
+	// Время ожидания ответа целиком
+	pub fn set_timeout(&mut self, timeout: Duration) {
+		self.timeout = timeout;
+	}
+
+	pub fn set_turnaround_delay(&mut self, delay: Duration) {
+		self.turnaround = delay;
+	}
+
+	pub fn transport(&self) -> &T {
+		&self.port
+	}
+
+	// Пауза между кадрами: 3.5 символа, но не меньше 1.75 мс.
+	// Транспорту без символьного времени пауза не требуется
+	pub fn frame_gap(&self) -> Duration {
+		self.port.char_time()
+			.map(|t| (t * 7 / 2).max(MIN_FRAME_GAP))
+			.unwrap_or_default()
+	}
+
+	pub fn transport_mut(&mut self) -> &mut T {
+		&mut self.port
+	}
+
+	pub fn into_transport(self) -> T {
+		self.port
+	}
+
+	// Отправка запроса pdu (код функции и данные) устройству unit.
+	// Возвращает данные ответа после кода функции. На широковещательный
+	// запрос ответ не ожидается, возвращается пустой вектор.
+	// response_len - правило длины ответа для пользовательских функций
+	pub fn transact(&mut self, unit: u8, pdu: &[u8], response_len: Option<QueryLen>) -> Result<Vec<u8>, ClientError> {
+		if unit == BROADCAST_ID {
+			self.send_without_response(unit, pdu)?;
+			return Ok(Vec::new());
+		}
+		let request = self.send(unit, pdu)?;
+
+		let len = self.read_response(response_len)?;
+		let response = &self.buf[..len];
+		if let Some(trace) = self.trace.as_mut() {
+			trace(FrameKind::Response, response, Some(&request[..request.len() - 2]));
+		}
+		let crc_rx = LittleEndian::read_u16(&response[len - 2..]);
+		if crc_rx != crc(&response[..len - 2]) {
+			return Err(ClientError::Crc);
+		}
+		if response[0] != unit {
+			return Err(invalid_response("ответ от другого устройства"));
+		}
+		let function = pdu[0];
+		if response[1] == function | 0x80 {
+			return Err(ClientError::Exception { function, code: response[2] });
+		}
+		if response[1] != function {
+			return Err(invalid_response("код функции не совпадает с запросом"));
+		}
+		Ok(response[2..len - 2].to_vec())
+	}
+
+	// Отправка запроса, на который устройство не отвечает (широковещательный
+	// запрос, Force Listen Only Mode). Пауза даёт устройствам выполнить запрос
+	fn send_without_response(&mut self, unit: u8, pdu: &[u8]) -> Result<(), ClientError> {
+		self.send(unit, pdu)?;
+		thread::sleep(self.turnaround);
+		Ok(())
+	}
+
+	// Возвращает отправленный кадр
+	fn send(&mut self, unit: u8, pdu: &[u8]) -> Result<Vec<u8>, ClientError> {
+		if pdu.is_empty() || pdu.len() + 3 > IN_BUF_SIZE {
+			return Err(ClientError::InvalidRequest("неверная длина PDU".into()));
+		}
+		let mut frame = Vec::with_capacity(pdu.len() + 3);
+		frame.push(unit);
+		frame.extend_from_slice(pdu);
+		let crc_tx = crc(&frame);
+		frame.extend_from_slice(&crc_tx.to_le_bytes());
+		if let Some(trace) = self.trace.as_mut() {
+			trace(FrameKind::Request, &frame, None);
+		}
+		self.port.write_frame(&frame)?;
+		Ok(frame)
+	}
+
+	// Чтение ответа в буфер. Возвращает длину кадра, включая CRC
+	fn read_response(&mut self, response_len: Option<QueryLen>) -> Result<usize, ClientError> {
+		let deadline = Instant::now() + self.timeout;
+		let mut pos = 0;
+		let mut len = usize::MAX;
+		loop {
+			if len == usize::MAX && pos >= 2 {
+				len = match response_len {
+					Some(rule) if self.buf[1] & 0x80 == 0 =>
+						rule.query_len(&self.buf[..pos]).map_err(|e| ClientError::InvalidResponse(e.message))?,
+					_ => get_response_len(&self.buf[..pos])?,
+				};
+			}
+			if pos >= len { return Ok(len); }
+
+			let now = Instant::now();
+			if now >= deadline { return Err(ClientError::Timeout); }
+			self.port.set_timeout(deadline - now)?;
+			let read_to = if len == usize::MAX { pos + 1 } else { len };
+			match self.port.read(&mut self.buf[pos..read_to]) {
+				Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "транспорт закрыт").into()),
+				Ok(n) => pos += n,
+				// Пауза на линии, время ожидания проверяется в начале цикла
+				Err(ref e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => {},
+				Err(e) => return Err(e.into()),
+			}
+		}
+	}
+
+	// Сравнение эха ответа с запросом. Для широковещательного запроса ответа нет
+	fn write_with_echo(&mut self, unit: u8, pdu: &[u8]) -> Result<(), ClientError> {
+		let data = self.transact(unit, pdu, None)?;
+		if unit != BROADCAST_ID && data != pdu[1..] {
+			return Err(invalid_response("эхо не совпадает с запросом"));
+		}
+		Ok(())
+	}
+
+	fn read_bits(&mut self, unit: u8, function: MbFunc, address: u16, quantity: u16) -> Result<Vec<bool>, ClientError> {
+		check_unit(unit)?;
+		check_quantity(quantity, 0x07D0)?;
+		let data = self.transact(unit, &address_pdu(function, address, quantity), None)?;
+		let byte_count = (quantity as usize + 7) / 8;
+		if data.len() != byte_count + 1 || data[0] as usize != byte_count {
+			return Err(invalid_response(STR_INVALID_BYTE_COUNT));
+		}
+		let mut bits = vec![0u8; quantity as usize];
+		unpack_bits(&data[1..], &mut bits);
+		Ok(bits.iter().map(|&b| b != 0).collect())
+	}
+
+	fn read_registers(&mut self, unit: u8, function: MbFunc, address: u16, quantity: u16) -> Result<Vec<u16>, ClientError> {
+		check_unit(unit)?;
+		check_quantity(quantity, 0x007D)?;
+		let data = self.transact(unit, &address_pdu(function, address, quantity), None)?;
+		registers(&data, quantity as usize)
+	}
+
+	pub fn read_coils(&mut self, unit: u8, address: u16, quantity: u16) -> Result<Vec<bool>, ClientError> {
+		self.read_bits(unit, MbFunc::ReadCoils, address, quantity)
+	}
+
+	pub fn read_discrete_inputs(&mut self, unit: u8, address: u16, quantity: u16) -> Result<Vec<bool>, ClientError> {
+		self.read_bits(unit, MbFunc::ReadDiscreteInputs, address, quantity)
+	}
+
+	pub fn read_holding_registers(&mut self, unit: u8, address: u16, quantity: u16) -> Result<Vec<u16>, ClientError> {
+		self.read_registers(unit, MbFunc::ReadHoldingRegisters, address, quantity)
+	}
+
+	pub fn read_input_registers(&mut self, unit: u8, address: u16, quantity: u16) -> Result<Vec<u16>, ClientError> {
+		self.read_registers(unit, MbFunc::ReadInputRegisters, address, quantity)
+	}
+
+	pub fn write_single_coil(&mut self, unit: u8, address: u16, value: bool) -> Result<(), ClientError> {
+		let value = if value { 0xFF00 } else { 0x0000 };
+		self.write_with_echo(unit, &address_pdu(MbFunc::WriteSingleCoil, address, value))
+	}
+
+	pub fn write_single_register(&mut self, unit: u8, address: u16, value: u16) -> Result<(), ClientError> {
+		self.write_with_echo(unit, &address_pdu(MbFunc::WriteSingleRegister, address, value))
+	}
+
+	pub fn write_multiple_coils(&mut self, unit: u8, address: u16, values: &[bool]) -> Result<(), ClientError> {
+		if values.is_empty() || values.len() > 0x07B0 { return Err(ClientError::InvalidRequest(STR_INVALID_QUANTITY.into())); }
+		let mut pdu = address_pdu(MbFunc::WriteMultipleCoils, address, values.len() as u16);
+		let bits: Vec<u8> = values.iter().map(|&v| v as u8).collect();
+		let mut packed = Vec::with_capacity((bits.len() + 7) / 8);
+		pack_bits(&bits, &mut packed);
+		pdu.push(packed.len() as u8);
+		pdu.extend_from_slice(&packed);
+		self.write_echo_header(unit, &pdu)
+	}
+
+	pub fn write_multiple_registers(&mut self, unit: u8, address: u16, values: &[u16]) -> Result<(), ClientError> {
+		if values.is_empty() || values.len() > 0x007B { return Err(ClientError::InvalidRequest(STR_INVALID_QUANTITY.into())); }
+		let mut pdu = address_pdu(MbFunc::WriteMultipleRegisters, address, values.len() as u16);
+		pdu.push((values.len() * 2) as u8);
+		for v in values.iter() { pdu.extend_from_slice(&v.to_be_bytes()); }
+		self.write_echo_header(unit, &pdu)
+	}
+
+	// Ответ на запись нескольких значений содержит только адрес и количество
+	fn write_echo_header(&mut self, unit: u8, pdu: &[u8]) -> Result<(), ClientError> {
+		let data = self.transact(unit, pdu, None)?;
+		if unit != BROADCAST_ID && data != pdu[1..5] {
+			return Err(invalid_response("эхо не совпадает с запросом"));
+		}
+		Ok(())
+	}
+
+	pub fn mask_write_register(&mut self, unit: u8, address: u16, and_mask: u16, or_mask: u16) -> Result<(), ClientError> {
+		let mut pdu = address_pdu(MbFunc::MaskWriteRegister, address, and_mask);
+		pdu.extend_from_slice(&or_mask.to_be_bytes());
+		self.write_with_echo(unit, &pdu)
+	}
+
+	pub fn read_write_multiple_registers(&mut self, unit: u8, read_address: u16, read_quantity: u16, write_address: u16, values: &[u16])
+		-> Result<Vec<u16>, ClientError>
+	{
+		check_unit(unit)?;
+		check_quantity(read_quantity, 0x007D)?;
+		if values.is_empty() || values.len() > 0x0079 { return Err(ClientError::InvalidRequest(STR_INVALID_QUANTITY.into())); }
+		let mut pdu = address_pdu(MbFunc::ReadWriteMultipleRegisters, read_address, read_quantity);
+		pdu.extend_from_slice(&write_address.to_be_bytes());
+		pdu.extend_from_slice(&(values.len() as u16).to_be_bytes());
+		pdu.push((values.len() * 2) as u8);
+		for v in values.iter() { pdu.extend_from_slice(&v.to_be_bytes()); }
+		let data = self.transact(unit, &pdu, None)?;
+		registers(&data, read_quantity as usize)
+	}
+
+	pub fn read_exception_status(&mut self, unit: u8) -> Result<u8, ClientError> {
+		check_unit(unit)?;
+		let data = self.transact(unit, &[MbFunc::ReadExceptionStatus as u8], None)?;
+		if data.len() != 1 { return Err(invalid_response(STR_INVALID_LENGTH)); }
+		Ok(data[0])
+	}
+
+	// Подфункция диагностики. Возвращает поле данных ответа.
+	// На Force Listen Only Mode устройство не отвечает
+	pub fn diagnostics(&mut self, unit: u8, sub_function: u16, data: u16) -> Result<u16, ClientError> {
+		let pdu = address_pdu(MbFunc::Diagnostics, sub_function, data);
+		if unit == BROADCAST_ID || sub_function == DIAG_FORCE_LISTEN_ONLY {
+			self.send_without_response(unit, &pdu)?;
+			return Ok(data);
+		}
+		let response = self.transact(unit, &pdu, None)?;
+		if response[..2] != pdu[1..3] {
+			return Err(invalid_response("подфункция не совпадает с запросом"));
+		}
+		Ok(BigEndian::read_u16(&response[2..4]))
+	}
+
+	pub fn comm_event_counter(&mut self, unit: u8) -> Result<CommEventCounter, ClientError> {
+		check_unit(unit)?;
+		let data = self.transact(unit, &[MbFunc::GetCommEventCounter as u8], None)?;
+		Ok(CommEventCounter {
+			status:      BigEndian::read_u16(&data[0..2]),
+			event_count: BigEndian::read_u16(&data[2..4]),
+		})
+	}
+
+	pub fn comm_event_log(&mut self, unit: u8) -> Result<CommEventLog, ClientError> {
+		check_unit(unit)?;
+		let data = self.transact(unit, &[MbFunc::GetCommEventLog as u8], None)?;
+		if data[0] < 6 || data.len() != data[0] as usize + 1 {
+			return Err(invalid_response(STR_INVALID_BYTE_COUNT));
+		}
+		Ok(CommEventLog {
+			status:        BigEndian::read_u16(&data[1..3]),
+			event_count:   BigEndian::read_u16(&data[3..5]),
+			message_count: BigEndian::read_u16(&data[5..7]),
+			events:        data[7..].to_vec(),
+		})
+	}
+
+	// Данные ответа Report Server ID без счётчика байт: идентификатор,
+	// индикатор работы и дополнительные данные, формат зависит от устройства
+	pub fn report_server_id(&mut self, unit: u8) -> Result<Vec<u8>, ClientError> {
+		check_unit(unit)?;
+		let data = self.transact(unit, &[MbFunc::ReportServerId as u8], None)?;
+		if data.len() != data[0] as usize + 1 {
+			return Err(invalid_response(STR_INVALID_BYTE_COUNT));
+		}
+		Ok(data[1..].to_vec())
+	}
+
+	pub fn read_fifo_queue(&mut self, unit: u8, address: u16) -> Result<Vec<u16>, ClientError> {
+		check_unit(unit)?;
+		let mut pdu = vec![MbFunc::ReadFifoQueue as u8];
+		pdu.extend_from_slice(&address.to_be_bytes());
+		let data = self.transact(unit, &pdu, None)?;
+		if data.len() < 4 { return Err(invalid_response(STR_INVALID_BYTE_COUNT)); }
+		let byte_count = BigEndian::read_u16(&data[0..2]) as usize;
+		let count = BigEndian::read_u16(&data[2..4]) as usize;
+		if byte_count != 2 + count * 2 || data.len() != byte_count + 2 {
+			return Err(invalid_response(STR_INVALID_BYTE_COUNT));
+		}
+		let mut values = vec![0u16; count];
+		BigEndian::read_u16_into(&data[4..], &mut values);
+		Ok(values)
+	}
+
+	pub fn read_file_record(&mut self, unit: u8, requests: &[FileRecordRequest]) -> Result<Vec<Vec<u16>>, ClientError> {
+		check_unit(unit)?;
+		let byte_count = requests.len() * FILE_SUB_REQUEST_LEN;
+		if requests.is_empty() || byte_count > 0xF5 {
+			return Err(ClientError::InvalidRequest("неверное количество подзапросов".into()));
+		}
+		let mut pdu = vec![MbFunc::ReadFileRecord as u8, byte_count as u8];
+		for r in requests.iter() {
+			pdu.push(FILE_REFERENCE_TYPE);
+			pdu.extend_from_slice(&r.file.to_be_bytes());
+			pdu.extend_from_slice(&r.record.to_be_bytes());
+			pdu.extend_from_slice(&r.length.to_be_bytes());
+		}
+		let data = self.transact(unit, &pdu, None)?;
+		if data.len() != data[0] as usize + 1 {
+			return Err(invalid_response(STR_INVALID_BYTE_COUNT));
+		}
+
+		let mut files = Vec::with_capacity(requests.len());
+		let mut pos = 1;
+		for r in requests.iter() {
+			let len = 1 + r.length as usize * 2;
+			if pos + 1 + len > data.len() || data[pos] as usize != len || data[pos + 1] != FILE_REFERENCE_TYPE {
+				return Err(invalid_response("неверный подответ"));
+			}
+			let mut records = vec![0u16; r.length as usize];
+			BigEndian::read_u16_into(&data[pos + 2..pos + 1 + len], &mut records);
+			files.push(records);
+			pos += 1 + len;
+		}
+		if pos != data.len() { return Err(invalid_response(STR_INVALID_BYTE_COUNT)); }
+		Ok(files)
+	}
+
+	// Подзапросы записи: номер файла, номер первой записи, значения
+	pub fn write_file_record(&mut self, unit: u8, requests: &[(u16, u16, &[u16])]) -> Result<(), ClientError> {
+		let mut pdu = vec![MbFunc::WriteFileRecord as u8, 0];
+		for &(file, record, values) in requests.iter() {
+			pdu.push(FILE_REFERENCE_TYPE);
+			pdu.extend_from_slice(&file.to_be_bytes());
+			pdu.extend_from_slice(&record.to_be_bytes());
+			pdu.extend_from_slice(&(values.len() as u16).to_be_bytes());
+			for v in values.iter() { pdu.extend_from_slice(&v.to_be_bytes()); }
+		}
+		let byte_count = pdu.len() - 2;
+		if requests.is_empty() || byte_count > 0xFB {
+			return Err(ClientError::InvalidRequest("неверное количество подзапросов".into()));
+		}
+		pdu[1] = byte_count as u8;
+		self.write_with_echo(unit, &pdu)
+	}
+
+	// Один запрос Read Device Identification. code - способ чтения (1 - 4)
+	pub fn read_device_identification(&mut self, unit: u8, code: u8, object_id: u8) -> Result<DeviceIdentificationResponse, ClientError> {
+		check_unit(unit)?;
+		let pdu = [MbFunc::EncapsulatedInterfaceTransport as u8, MEI_READ_DEVICE_ID, code, object_id];
+		let data = self.transact(unit, &pdu, None)?;
+		if data[0] != MEI_READ_DEVICE_ID || data[1] != code {
+			return Err(invalid_response("MEI type или код чтения не совпадает с запросом"));
+		}
+		let mut objects = Vec::with_capacity(data[5] as usize);
+		let mut pos = 6;
+		for _ in 0..data[5] {
+			let len = data[pos + 1] as usize;
+			objects.push((data[pos], data[pos + 2..pos + 2 + len].to_vec()));
+			pos += 2 + len;
+		}
+		Ok(DeviceIdentificationResponse {
+			code,
+			conformity:   data[2],
+			more_follows: data[3] == 0xFF,
+			next_object:  data[4],
+			objects,
+		})
+	}
+
+	// Чтение всех объектов категории с продолжением по "more follows"
+	pub fn read_all_device_identification(&mut self, unit: u8, code: u8) -> Result<Vec<(u8, Vec<u8>)>, ClientError> {
+		let mut objects = Vec::new();
+		let mut object_id = 0;
+		// Объектов не больше 256, каждый ответ содержит хотя бы один
+		for _ in 0..=0xFF {
+			let response = self.read_device_identification(unit, code, object_id)?;
+			objects.extend(response.objects);
+			if !response.more_follows { return Ok(objects); }
+			object_id = response.next_object;
+		}
+		Err(invalid_response("бесконечная последовательность \"more follows\""))
+	}
+
+	// Пользовательская функция: данные запроса после кода функции
+	// и правило длины ответа
+	pub fn custom(&mut self, unit: u8, function: u8, data: &[u8], response_len: QueryLen) -> Result<Vec<u8>, ClientError> {
+		let mut pdu = Vec::with_capacity(1 + data.len());
+		pdu.push(function);
+		pdu.extend_from_slice(data);
+		self.transact(unit, &pdu, Some(response_len))
+	}
+}
+
+fn check_unit(unit: u8) -> Result<(), ClientError> {
+	if unit == BROADCAST_ID {
+		return Err(ClientError::InvalidRequest("чтение не может быть широковещательным".into()));
+	}
+	Ok(())
+}
+
+fn check_quantity(quantity: u16, max: u16) -> Result<(), ClientError> {
+	if quantity == 0 || quantity > max {
+		return Err(ClientError::InvalidRequest(STR_INVALID_QUANTITY.into()));
+	}
+	Ok(())
+}
+
+// PDU вида: код функции, адрес, 16-битное значение
+fn address_pdu(function: MbFunc, address: u16, value: u16) -> Vec<u8> {
+	let mut pdu = Vec::with_capacity(8);
+	pdu.push(function as u8);
+	pdu.extend_from_slice(&address.to_be_bytes());
+	pdu.extend_from_slice(&value.to_be_bytes());
+	pdu
+}
+
+// Регистры из ответа: счётчик байт и значения
+fn registers(data: &[u8], quantity: usize) -> Result<Vec<u16>, ClientError> {
+	if data.len() != quantity * 2 + 1 || data[0] as usize != quantity * 2 {
+		return Err(invalid_response(STR_INVALID_BYTE_COUNT));
+	}
+	let mut values = vec![0u16; quantity];
+	BigEndian::read_u16_into(&data[1..], &mut values);
+	Ok(values)
+}
+
+// Длина кадра ответа RTU, включая адрес устройства и CRC.
+// Возвращает Ok(usize::MAX), если длину пока определить нельзя
+pub fn get_response_len(response: &[u8]) -> Result<usize, ClientError> {
+	if response.len() < 2 { return Ok(usize::MAX); }
+	let function = response[1];
+	if function & 0x80 != 0 { return Ok(5); }
+	let len = match num::FromPrimitive::from_u8(function) {
+		Some(MbFunc::ReadCoils) | Some(MbFunc::ReadDiscreteInputs)
+		| Some(MbFunc::ReadHoldingRegisters) | Some(MbFunc::ReadInputRegisters)
+		| Some(MbFunc::ReadWriteMultipleRegisters) | Some(MbFunc::GetCommEventLog)
+		| Some(MbFunc::ReportServerId) | Some(MbFunc::ReadFileRecord) | Some(MbFunc::WriteFileRecord) => {
+			if response.len() > 2 { response[2] as usize + 2 + 1 + 2 }
+			else { usize::MAX }
+		},
+		Some(MbFunc::WriteSingleCoil) | Some(MbFunc::WriteSingleRegister)
+		| Some(MbFunc::WriteMultipleCoils) | Some(MbFunc::WriteMultipleRegisters)
+		| Some(MbFunc::Diagnostics) | Some(MbFunc::GetCommEventCounter) => 8,
+		Some(MbFunc::MaskWriteRegister) => 10,
+		Some(MbFunc::ReadExceptionStatus) => 5,
+		Some(MbFunc::ReadFifoQueue) => {
+			if response.len() > 3 { BigEndian::read_u16(&response[2..4]) as usize + 2 + 2 + 2 }
+			else { usize::MAX }
+		},
+		Some(MbFunc::EncapsulatedInterfaceTransport) => device_identification_len(response),
+		None => return Err(invalid_response(STR_ILLEGAL_FUNCTION)),
+	};
+	if len != usize::MAX && len > IN_BUF_SIZE {
+		return Err(invalid_response("слишком длинный ответ"));
+	}
+	Ok(len)
+}
+
+// Ответ Read Device Identification: заголовок из 8 байт, затем объекты
+// (идентификатор, длина, значение)
+fn device_identification_len(response: &[u8]) -> usize {
+	const HEADER_LEN: usize = 8;
+	if response.len() < HEADER_LEN { return usize::MAX; }
+	let mut pos = HEADER_LEN;
+	for _ in 0..response[HEADER_LEN - 1] {
+		if response.len() < pos + 2 { return usize::MAX; }
+		pos += 2 + response[pos + 1] as usize;
+	}
+	pos + 2
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::{ TcpListener, TcpStream };
+	use crate::server::{ Server, Framing };
+	use crate::server::device::Device;
+	use crate::transport::MemoryTransport;
+
+	fn rtu(adu: &[u8]) -> Vec<u8> {
+		let mut frame = adu.to_vec();
+		frame.extend_from_slice(&crc(adu).to_le_bytes());
+		frame
+	}
+
+	fn client(responses: &[Vec<u8>]) -> Client<MemoryTransport> {
+		let mut transport = MemoryTransport::new();
+		for r in responses.iter() { transport.push_frame(r); }
+		Client::new(transport)
+	}
+
+	#[test]
+	fn response_validation() {
+		let mut c = client(&[rtu(&[1, 0x03, 0x04, 0x12, 0x34, 0x00, 0x01])]);
+		assert_eq!(c.read_holding_registers(1, 0x10, 2).unwrap(), vec![0x1234, 0x0001]);
+		assert_eq!(c.transport().written(), &[rtu(&[1, 0x03, 0x00, 0x10, 0x00, 0x02])]);
+
+		let mut c = client(&[rtu(&[1, 0x83, 0x02])]);
+		assert_eq!(c.read_holding_registers(1, 0, 1).unwrap_err().exception(), Some(MbExc::IllegalDataAddress));
+
+		let mut corrupted = rtu(&[1, 0x06, 0x00, 0x01, 0x00, 0x07]);
+		corrupted[6] ^= 0xFF;
+		let mut c = client(&[corrupted]);
+		assert!(matches!(c.write_single_register(1, 1, 7), Err(ClientError::Crc)));
+
+		let mut c = client(&[rtu(&[1, 0x06, 0x00, 0x01, 0x00, 0x08])]);
+		assert!(matches!(c.write_single_register(1, 1, 7), Err(ClientError::InvalidResponse(_))));
+
+		let mut c = client(&[rtu(&[2, 0x05, 0x00, 0x01, 0xFF, 0x00])]);
+		assert!(matches!(c.write_single_coil(1, 1, true), Err(ClientError::InvalidResponse(_))));
+
+		let mut c = client(&[]);
+		c.set_turnaround_delay(Duration::from_millis(0));
+		c.write_single_coil(BROADCAST_ID, 1, true).unwrap();
+		// На Force Listen Only Mode ответ не ожидается
+		assert_eq!(c.diagnostics(1, DIAG_FORCE_LISTEN_ONLY, 0).unwrap(), 0);
+		assert_eq!(c.transport().written().last().unwrap(), &rtu(&[1, 0x08, 0x00, 0x04, 0x00, 0x00]));
+		assert!(matches!(c.read_coils(BROADCAST_ID, 0, 1), Err(ClientError::InvalidRequest(_))));
+		assert!(matches!(c.read_coils(1, 0, 0), Err(ClientError::InvalidRequest(_))));
+		assert!(matches!(c.read_coils(1, 0, 1), Err(ClientError::Io(_))));
+	}
+
+	#[test]
+	fn trace_frames() {
+		let frames = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+		let traced = frames.clone();
+		let mut c = client(&[rtu(&[1, 0x06, 0x00, 0x01, 0x00, 0x07])]);
+		c.set_trace(move |kind, frame, request| traced.lock().unwrap().push((kind, frame.to_vec(), request.map(|r| r.to_vec()))));
+		c.write_single_register(1, 1, 7).unwrap();
+		assert_eq!(*frames.lock().unwrap(), vec![
+			(FrameKind::Request, rtu(&[1, 0x06, 0x00, 0x01, 0x00, 0x07]), None),
+			(FrameKind::Response, rtu(&[1, 0x06, 0x00, 0x01, 0x00, 0x07]), Some(vec![1, 0x06, 0x00, 0x01, 0x00, 0x07])),
+		]);
+	}
+
+	#[test]
+	fn response_len() {
+		assert_eq!(get_response_len(&[1]).unwrap(), usize::MAX);
+		assert_eq!(get_response_len(&[1, 0x83]).unwrap(), 5);
+		assert_eq!(get_response_len(&[1, 0x03]).unwrap(), usize::MAX);
+		assert_eq!(get_response_len(&[1, 0x03, 4]).unwrap(), 9);
+		assert_eq!(get_response_len(&[1, 0x16]).unwrap(), 10);
+		assert_eq!(get_response_len(&[1, 0x18, 0x00, 0x06]).unwrap(), 12);
+		assert_eq!(get_response_len(&[1, 0x2B, 0x0E, 0x01, 0x81, 0x00, 0x00, 1, 0x00]).unwrap(), usize::MAX);
+		assert_eq!(get_response_len(&[1, 0x2B, 0x0E, 0x01, 0x81, 0x00, 0x00, 1, 0x00, 3]).unwrap(), 15);
+		assert!(get_response_len(&[1, 0x42]).is_err());
+	}
+
+	// Клиент и сервер, соединённые через TCP (кадры RTU поверх TCP)
+	#[test]
+	fn round_trip_with_server() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let mut device = Device::new();
+		device.add_fifo(10);
+		device.push_fifo(10, 0xAAAA).unwrap();
+		device.add_file(4, 10).unwrap();
+		let device = device.shared();
+		let server_device = device.clone();
+		thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			Server::new(stream, 1, server_device, Framing::Rtu).start().unwrap();
+		});
+		let mut c = Client::new(TcpStream::connect(addr).unwrap());
+
+		c.write_single_coil(1, 3, true).unwrap();
+		c.write_multiple_coils(1, 4, &[true, false, true]).unwrap();
+		assert_eq!(c.read_coils(1, 2, 6).unwrap(), vec![false, true, true, false, true, false]);
+		assert_eq!(c.read_discrete_inputs(1, 0, 3).unwrap(), vec![false; 3]);
+
+		c.write_single_register(1, 0, 0x1234).unwrap();
+		c.write_multiple_registers(1, 1, &[5, 6]).unwrap();
+		c.mask_write_register(1, 0, 0x00FF, 0x5600).unwrap();
+		assert_eq!(c.read_holding_registers(1, 0, 3).unwrap(), vec![0x5634, 5, 6]);
+		assert_eq!(c.read_write_multiple_registers(1, 0, 2, 1, &[9]).unwrap(), vec![0x5634, 9]);
+		assert_eq!(c.read_input_registers(1, 0, 2).unwrap(), vec![0, 0]);
+
+		assert_eq!(c.read_exception_status(1).unwrap(), 0);
+		assert_eq!(c.diagnostics(1, 0x0000, 0xA537).unwrap(), 0xA537);
+		assert_eq!(c.comm_event_counter(1).unwrap().status, 0);
+		assert!(!c.comm_event_log(1).unwrap().events.is_empty());
+		assert_eq!(*c.report_server_id(1).unwrap().last().unwrap(), 0xFF);
+		assert_eq!(c.read_fifo_queue(1, 10).unwrap(), vec![0xAAAA]);
+
+		c.write_file_record(1, &[(4, 2, &[7, 8][..])]).unwrap();
+		assert_eq!(
+			c.read_file_record(1, &[FileRecordRequest { file: 4, record: 1, length: 3 }]).unwrap(),
+			vec![vec![0, 7, 8]]
+		);
+		let objects = c.read_all_device_identification(1, 0x01).unwrap();
+		assert_eq!(objects.iter().map(|o| o.0).collect::<Vec<u8>>(), vec![0, 1, 2]);
+
+		assert_eq!(c.read_holding_registers(1, 2000, 1).unwrap_err().exception(), Some(MbExc::IllegalDataAddress));
+		assert_eq!(device.lock().unwrap().file(4).unwrap()[2], 7);
+	}
+}
@@ -0,0 +1,245 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Определение параметров линии: перебор скоростей, чётности и стоповых бит
+// с пробным запросом к устройству до получения ответа с верной CRC
+//------------------------------------------------------------------------------
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use serialport::{ SerialPort, Parity, StopBits, ClearBuffer };
+
+use crate::client::{ Client, ClientError };
+use crate::client::scan::probe_pdu;
+use crate::transport::Transport;
+
+// Скорости в порядке распространённости
+pub const BAUD_RATES: [u32; 8] = [9600, 19200, 38400, 57600, 115200, 4800, 2400, 1200];
+
+// Чётность и стоповые биты. 8N1 проверяется раньше 8N2: устройство 8N1
+// принимает кадры 8N2, и наоборот обычно нет
+pub const FRAME_FORMATS: [(Parity, StopBits); 4] = [
+	(Parity::Even, StopBits::One),
+	(Parity::Odd, StopBits::One),
+	(Parity::None, StopBits::One),
+	(Parity::None, StopBits::Two),
+];
+
+// Запас времени ответа на передачу пробного запроса и ответа (в символах)
+const PROBE_CHARS: u32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineSettings {
+	pub baud_rate: u32,
+	pub parity:    Parity,
+	pub stop_bits: StopBits,
+}
+
+impl fmt::Display for LineSettings {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let parity = match self.parity {
+			Parity::None => 'N',
+			Parity::Odd  => 'O',
+			Parity::Even => 'E',
+		};
+		let stop_bits = match self.stop_bits {
+			StopBits::One => 1,
+			StopBits::Two => 2,
+		};
+		write!(f, "{} 8{}{}", self.baud_rate, parity, stop_bits)
+	}
+}
+
+// Все сочетания параметров в порядке перебора
+pub fn candidates() -> Vec<LineSettings> {
+	BAUD_RATES.iter()
+		.flat_map(|&baud_rate| FRAME_FORMATS.iter().map(move |&(parity, stop_bits)| LineSettings { baud_rate, parity, stop_bits }))
+		.collect()
+}
+
+// Транспорт, параметры линии которого можно менять
+pub trait LineConfig: Transport {
+	fn line_settings(&self) -> io::Result<LineSettings>;
+
+	// Переход на новые параметры. Байты, принятые на прежних параметрах,
+	// не относятся к ответу и отбрасываются
+	fn set_line_settings(&mut self, settings: &LineSettings) -> io::Result<()>;
+}
+
+impl LineConfig for Box<dyn SerialPort> {
+	fn line_settings(&self) -> io::Result<LineSettings> {
+		Ok(LineSettings {
+			baud_rate: self.baud_rate()?,
+			parity:    self.parity()?,
+			stop_bits: self.stop_bits()?,
+		})
+	}
+
+	fn set_line_settings(&mut self, settings: &LineSettings) -> io::Result<()> {
+		self.set_baud_rate(settings.baud_rate)?;
+		self.set_parity(settings.parity)?;
+		self.set_stop_bits(settings.stop_bits)?;
+		self.clear(ClearBuffer::Input)?;
+		Ok(())
+	}
+}
+
+// Перебор параметров линии с пробным запросом (см. scan::probe_pdu) к устройству unit.
+// Ответ исключением тоже считается успехом: кадр принят с верной CRC.
+// timeout - ожидание ответа сверх времени передачи кадров.
+// Возвращает первые подошедшие параметры, порт остаётся настроенным на них.
+// Если ничего не подошло, восстанавливаются исходные параметры
+pub fn detect<T, F>(client: &mut Client<T>, unit: u8, function: u8, address: u16, timeout: Duration, mut on_try: F)
+	-> Result<Option<LineSettings>, ClientError>
+	where T: LineConfig, F: FnMut(&LineSettings, &Result<Vec<u8>, ClientError>)
+{
+	let pdu = probe_pdu(function, address).map_err(ClientError::InvalidRequest)?;
+	let initial = client.transport().line_settings()?;
+
+	for settings in candidates() {
+		client.transport_mut().set_line_settings(&settings)?;
+		let char_time = client.transport().char_time().unwrap_or_default();
+		client.set_timeout(timeout + char_time * PROBE_CHARS);
+
+		let result = client.transact(unit, &pdu, None);
+		on_try(&settings, &result);
+		match result {
+			Ok(_) | Err(ClientError::Exception { .. }) => return Ok(Some(settings)),
+			Err(ClientError::Io(e)) => return Err(ClientError::Io(e)),
+			Err(_) => thread::sleep(client.frame_gap()),
+		}
+	}
+	client.transport_mut().set_line_settings(&initial)?;
+	Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::ErrorKind;
+	use crate::server::device::Device;
+	use crate::server::formal::crc;
+
+	// Устройство на линии с параметрами device_settings. На другой скорости
+	// запрос не принимается, при несовпадении формата кадра ответ искажается
+	struct Line {
+		settings:        LineSettings,
+		device_settings: LineSettings,
+		device:          Device,
+		response:        Vec<u8>,
+	}
+
+	impl Transport for Line {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			if self.response.is_empty() { return Err(ErrorKind::TimedOut.into()); }
+			let n = buf.len().min(self.response.len());
+			buf[..n].copy_from_slice(&self.response[..n]);
+			self.response.drain(..n);
+			Ok(n)
+		}
+
+		fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+			if self.settings.baud_rate != self.device_settings.baud_rate { return Ok(()); }
+			let query = &frame[..frame.len() - 2];
+			let mut response = vec![query[0]];
+			match self.device.process_bus_query(query) {
+				Some(Ok(data)) => {
+					response.push(query[1]);
+					response.extend_from_slice(&data);
+				},
+				Some(Err(e)) => {
+					response.push(query[1] | 0x80);
+					response.push(e.exc as u8);
+				},
+				None => return Ok(()),
+			}
+			let crc_tx = crc(&response);
+			response.extend_from_slice(&crc_tx.to_le_bytes());
+			if self.settings != self.device_settings {
+				let last = response.len() - 1;
+				response[last] ^= 0xFF;
+			}
+			self.response = response;
+			Ok(())
+		}
+
+		fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	impl LineConfig for Line {
+		fn line_settings(&self) -> io::Result<LineSettings> {
+			Ok(self.settings)
+		}
+
+		fn set_line_settings(&mut self, settings: &LineSettings) -> io::Result<()> {
+			self.settings = *settings;
+			self.response.clear();
+			Ok(())
+		}
+	}
+
+	fn client(device_settings: LineSettings) -> Client<Line> {
+		let settings = LineSettings { baud_rate: 9600, parity: Parity::None, stop_bits: StopBits::One };
+		Client::new(Line { settings, device_settings, device: Device::new(), response: Vec::new() })
+	}
+
+	// Перебор до найденных параметров. Возвращает найденные параметры
+	// и результаты попыток: (параметры, ответ получен, ошибка CRC)
+	fn run(client: &mut Client<Line>, address: u16) -> (Option<LineSettings>, Vec<(LineSettings, bool, bool)>) {
+		let mut tries = Vec::new();
+		let found = detect(client, 1, 0x03, address, Duration::from_millis(1), |settings, result| {
+			tries.push((*settings, result.is_ok(), matches!(result, Err(ClientError::Crc))));
+		}).unwrap();
+		(found, tries)
+	}
+
+	#[test]
+	fn detect_settings() {
+		let target = LineSettings { baud_rate: 19200, parity: Parity::Odd, stop_bits: StopBits::One };
+		let mut c = client(target);
+		let (found, tries) = run(&mut c, 0);
+		assert_eq!(found, Some(target));
+		assert_eq!(c.transport().settings, target);
+		// Четыре формата на 9600 без ответа, затем 19200 8E1 с искажённым ответом
+		assert_eq!(tries.len(), 6);
+		assert!(tries[..4].iter().all(|&(_, ok, crc)| !ok && !crc));
+		assert!(!tries[4].1 && tries[4].2);
+		assert!(tries[5].1);
+	}
+
+	#[test]
+	fn exception_is_a_hit() {
+		let target = LineSettings { baud_rate: 9600, parity: Parity::None, stop_bits: StopBits::Two };
+		let mut c = client(target);
+		let (found, tries) = run(&mut c, 2000);
+		assert_eq!(found, Some(target));
+		assert!(!tries.last().unwrap().1);
+	}
+
+	#[test]
+	fn restore_settings_when_not_found() {
+		let mut c = client(LineSettings { baud_rate: 300, parity: Parity::Even, stop_bits: StopBits::One });
+		let initial = c.transport().settings;
+		let (found, tries) = run(&mut c, 0);
+		assert_eq!(found, None);
+		assert_eq!(tries.len(), candidates().len());
+		assert_eq!(c.transport().settings, initial);
+	}
+
+	#[test]
+	fn candidate_order() {
+		let all = candidates();
+		assert_eq!(all.len(), BAUD_RATES.len() * FRAME_FORMATS.len());
+		assert_eq!(all[0].to_string(), "9600 8E1");
+		assert_eq!(all[3].to_string(), "9600 8N2");
+		assert_eq!(all.last().unwrap().to_string(), "1200 8N2");
+	}
+}
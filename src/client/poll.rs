@@ -0,0 +1,331 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Циклический опрос: список блоков для чтения и планировщик запросов
+//
+// Формат списка опроса - TOML. Каждый элемент задаёт устройство, функцию
+// чтения (0x01 - 0x04), начальный адрес, количество и период опроса в мс:
+//
+//     [[item]]
+//     name = "pump 1 status"
+//     unit = 1
+//     function = 0x03
+//     address = 100
+//     count = 10
+//     interval = 500
+//
+// Планировщик выполняет ближайший по сроку опрос, выдерживая паузу
+// между кадрами на общей линии, и повторяет запрос при отсутствии ответа
+// или ошибке CRC. Для каждого элемента хранится последнее прочитанное
+// значение, время его получения и последняя ошибка.
+//------------------------------------------------------------------------------
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use serde::Deserialize;
+
+use crate::client::{ Client, ClientError };
+use crate::server::formal::{ MbFunc, BROADCAST_ID };
+use crate::transport::Transport;
+
+// Количество повторов запроса по умолчанию
+pub const DEFAULT_RETRIES: u32 = 2;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PollItem {
+	#[serde(default)]
+	pub name:     String,
+	pub unit:     u8,
+	pub function: u8,
+	pub address:  u16,
+	pub count:    u16,
+	// Период опроса в мс
+	pub interval: u64,
+}
+
+impl PollItem {
+	fn check(&self) -> Result<(), String> {
+		if self.unit == BROADCAST_ID || self.unit > 247 {
+			return Err(format!("неверный адрес устройства {}", self.unit));
+		}
+		let max = match num::FromPrimitive::from_u8(self.function) {
+			Some(MbFunc::ReadCoils) | Some(MbFunc::ReadDiscreteInputs) => 0x07D0,
+			Some(MbFunc::ReadHoldingRegisters) | Some(MbFunc::ReadInputRegisters) => 0x007D,
+			_ => return Err(format!("функция 0x{:02X} не поддерживается, используйте 0x01 - 0x04", self.function)),
+		};
+		if self.count == 0 || self.count > max {
+			return Err(format!("количество должно быть от 1 до {}", max));
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Display for PollItem {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if !self.name.is_empty() { write!(f, "{} ", self.name)?; }
+		write!(f, "[{}: 0x{:02X} {}+{}]", self.unit, self.function, self.address, self.count)
+	}
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PollList {
+	#[serde(default)]
+	pub item: Vec<PollItem>,
+}
+
+#[derive(Debug)]
+pub enum PollListError {
+	Io(io::Error),
+	Parse(toml::de::Error),
+	// Неверно описан элемент списка (нумерация с 0)
+	InvalidItem { index: usize, message: String },
+}
+
+impl fmt::Display for PollListError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PollListError::Io(e) => write!(f, "ошибка чтения: {}", e),
+			PollListError::Parse(e) => write!(f, "ошибка разбора: {}", e),
+			PollListError::InvalidItem { index, message } => write!(f, "элемент {}: {}", index, message),
+		}
+	}
+}
+
+impl std::error::Error for PollListError {}
+
+impl From<io::Error> for PollListError {
+	fn from(e: io::Error) -> PollListError { PollListError::Io(e) }
+}
+
+impl From<toml::de::Error> for PollListError {
+	fn from(e: toml::de::Error) -> PollListError { PollListError::Parse(e) }
+}
+
+impl PollList {
+	pub fn load(path: &Path) -> Result<PollList, PollListError> {
+		let text = fs::read_to_string(path)?;
+		PollList::parse(&text)
+	}
+
+	pub fn parse(text: &str) -> Result<PollList, PollListError> {
+		let list: PollList = toml::from_str(text)?;
+		for (index, item) in list.item.iter().enumerate() {
+			item.check().map_err(|message| PollListError::InvalidItem { index, message })?;
+		}
+		Ok(list)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PollValue {
+	Bits(Vec<bool>),
+	Registers(Vec<u16>),
+}
+
+// Состояние элемента опроса
+#[derive(Debug, Default)]
+pub struct PollStatus {
+	// Последнее успешно прочитанное значение
+	pub value:    Option<PollValue>,
+	// Время получения value
+	pub updated:  Option<Instant>,
+	// Ошибка последнего опроса, None - опрос успешен
+	pub error:    Option<ClientError>,
+	pub polls:    u32,
+	pub failures: u32,
+}
+
+impl PollStatus {
+	// Время, прошедшее с получения последнего значения
+	pub fn age(&self) -> Option<Duration> {
+		self.updated.map(|t| t.elapsed())
+	}
+}
+
+pub struct Poller<T: Transport> {
+	client:   Client<T>,
+	items:    Vec<PollItem>,
+	status:   Vec<PollStatus>,
+	// Срок следующего опроса каждого элемента
+	next:     Vec<Instant>,
+	retries:  u32,
+	gap:      Duration,
+	// Окончание последнего обмена на линии
+	last:     Option<Instant>,
+}
+
+impl<T: Transport> Poller<T> {
+	pub fn new(client: Client<T>, items: Vec<PollItem>) -> Result<Poller<T>, String> {
+		for item in items.iter() {
+			item.check().map_err(|e| format!("{}: {}", item, e))?;
+		}
+		let gap = client.frame_gap();
+		let now = Instant::now();
+		Ok(Poller {
+			client,
+			status:  items.iter().map(|_| PollStatus::default()).collect(),
+			next:    vec![now; items.len()],
+			items,
+			retries: DEFAULT_RETRIES,
+			gap,
+			last:    None,
+		})
+	}
+
+	// Количество повторов после первой неудачной попытки
+	pub fn set_retries(&mut self, retries: u32) {
+		self.retries = retries;
+	}
+
+	// Пауза между концом ответа и следующим запросом
+	pub fn set_gap(&mut self, gap: Duration) {
+		self.gap = gap;
+	}
+
+	pub fn items(&self) -> &[PollItem] {
+		&self.items
+	}
+
+	pub fn status(&self, index: usize) -> &PollStatus {
+		&self.status[index]
+	}
+
+	pub fn client_mut(&mut self) -> &mut Client<T> {
+		&mut self.client
+	}
+
+	// Ожидание срока ближайшего элемента и его опрос. Возвращает индекс элемента
+	pub fn poll_next(&mut self) -> Option<usize> {
+		let (index, &due) = self.next.iter().enumerate().min_by_key(|&(_, t)| *t)?;
+		let now = Instant::now();
+		if due > now { thread::sleep(due - now); }
+
+		let item = self.items[index].clone();
+		let mut result = self.read(&item);
+		for _ in 0..self.retries {
+			match result {
+				Err(ClientError::Timeout) | Err(ClientError::Crc) => result = self.read(&item),
+				_ => break,
+			}
+		}
+
+		let status = &mut self.status[index];
+		status.polls += 1;
+		match result {
+			Ok(value) => {
+				status.value = Some(value);
+				status.updated = Some(Instant::now());
+				status.error = None;
+			},
+			Err(e) => {
+				status.failures += 1;
+				status.error = Some(e);
+			},
+		}
+		// При перегрузке линии срок не уходит в прошлое, и элемент
+		// выполняется после ранее просроченных
+		self.next[index] = (due + Duration::from_millis(item.interval)).max(Instant::now());
+		Some(index)
+	}
+
+	// Бесконечный опрос с вызовом on_update после каждого элемента
+	pub fn run<F>(&mut self, mut on_update: F)
+		where F: FnMut(&PollItem, &PollStatus)
+	{
+		while let Some(index) = self.poll_next() {
+			on_update(&self.items[index], &self.status[index]);
+		}
+	}
+
+	fn read(&mut self, item: &PollItem) -> Result<PollValue, ClientError> {
+		if let Some(last) = self.last {
+			let ready = last + self.gap;
+			let now = Instant::now();
+			if ready > now { thread::sleep(ready - now); }
+		}
+		let result = match num::FromPrimitive::from_u8(item.function) {
+			Some(MbFunc::ReadCoils) =>
+				self.client.read_coils(item.unit, item.address, item.count).map(PollValue::Bits),
+			Some(MbFunc::ReadDiscreteInputs) =>
+				self.client.read_discrete_inputs(item.unit, item.address, item.count).map(PollValue::Bits),
+			Some(MbFunc::ReadHoldingRegisters) =>
+				self.client.read_holding_registers(item.unit, item.address, item.count).map(PollValue::Registers),
+			Some(MbFunc::ReadInputRegisters) =>
+				self.client.read_input_registers(item.unit, item.address, item.count).map(PollValue::Registers),
+			_ => Err(ClientError::InvalidRequest(format!("функция 0x{:02X} не поддерживается", item.function))),
+		};
+		self.last = Some(Instant::now());
+		result
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::server::formal::crc;
+	use crate::transport::MemoryTransport;
+
+	fn rtu(adu: &[u8]) -> Vec<u8> {
+		let mut frame = adu.to_vec();
+		frame.extend_from_slice(&crc(adu).to_le_bytes());
+		frame
+	}
+
+	#[test]
+	fn parse_poll_list() {
+		let list = PollList::parse("[[item]]\nunit = 1\nfunction = 0x03\naddress = 100\ncount = 2\ninterval = 500\n").unwrap();
+		assert_eq!(list.item.len(), 1);
+		assert_eq!(list.item[0].address, 100);
+		assert!(matches!(
+			PollList::parse("[[item]]\nunit = 1\nfunction = 6\naddress = 0\ncount = 1\ninterval = 1\n"),
+			Err(PollListError::InvalidItem { index: 0, .. })
+		));
+		assert!(matches!(
+			PollList::parse("[[item]]\nunit = 1\nfunction = 3\naddress = 0\ncount = 126\ninterval = 1\n"),
+			Err(PollListError::InvalidItem { .. })
+		));
+	}
+
+	#[test]
+	fn schedule_and_retries() {
+		let mut transport = MemoryTransport::new();
+		transport.push_frame(&rtu(&[1, 0x03, 0x02, 0x00, 0x07]));
+		// Ответ с ошибкой CRC, затем повтор
+		let mut corrupted = rtu(&[2, 0x01, 0x01, 0x01]);
+		corrupted[3] ^= 0xFF;
+		transport.push_frame(&corrupted);
+		transport.push_frame(&rtu(&[2, 0x01, 0x01, 0x01]));
+		transport.push_frame(&rtu(&[1, 0x83, 0x02]));
+
+		let items = vec![
+			PollItem { name: "fast".into(), unit: 1, function: 3, address: 0, count: 1, interval: 20 },
+			PollItem { name: "slow".into(), unit: 2, function: 1, address: 5, count: 1, interval: 60000 },
+		];
+		let mut poller = Poller::new(Client::new(transport), items).unwrap();
+		assert_eq!(poller.poll_next(), Some(0));
+		assert_eq!(poller.status(0).value, Some(PollValue::Registers(vec![7])));
+
+		assert_eq!(poller.poll_next(), Some(1));
+		assert_eq!(poller.status(1).value, Some(PollValue::Bits(vec![true])));
+		assert!(poller.status(1).error.is_none());
+		assert_eq!(poller.client_mut().transport().written().len(), 3);
+
+		// Медленный элемент ещё не готов, быстрый возвращает исключение и сохраняет значение
+		assert_eq!(poller.poll_next(), Some(0));
+		let status = poller.status(0);
+		assert!(status.error.as_ref().unwrap().exception().is_some());
+		assert_eq!(status.value, Some(PollValue::Registers(vec![7])));
+		assert_eq!((status.polls, status.failures), (2, 1));
+		assert!(status.age().unwrap() >= Duration::from_millis(20));
+	}
+}
@@ -0,0 +1,144 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Поиск устройств на линии: пробный запрос по каждому адресу
+// и классификация ответа (ответ, исключение, молчание)
+//------------------------------------------------------------------------------
+use std::ops::RangeInclusive;
+use std::thread;
+
+use crate::client::{ Client, ClientError };
+use crate::server::formal::MbFunc;
+use crate::server::identification::{ MEI_READ_DEVICE_ID, READ_BASIC };
+use crate::transport::Transport;
+
+// Адреса, допустимые для устройств на линии
+pub const UNIT_IDS: RangeInclusive<u8> = 1..=247;
+
+#[derive(Debug)]
+pub enum ScanResult {
+	// Устройство ответило на пробный запрос
+	Answered,
+	// Устройство ответило исключением с указанным кодом
+	Exception(u8),
+	// Ответа нет
+	Silent,
+	// Получен повреждённый или не соответствующий запросу ответ
+	Invalid(ClientError),
+}
+
+// PDU пробного запроса. Поддерживаются только функции без записи:
+// чтение одной ячейки по адресу address, Diagnostics (Return Query Data
+// с данными address), Read FIFO Queue и запросы состояния устройства
+pub fn probe_pdu(function: u8, address: u16) -> Result<Vec<u8>, String> {
+	let mut pdu = vec![function];
+	match num::FromPrimitive::from_u8(function) {
+		Some(MbFunc::ReadCoils) | Some(MbFunc::ReadDiscreteInputs)
+		| Some(MbFunc::ReadHoldingRegisters) | Some(MbFunc::ReadInputRegisters) => {
+			pdu.extend_from_slice(&address.to_be_bytes());
+			pdu.extend_from_slice(&1u16.to_be_bytes());
+		},
+		Some(MbFunc::Diagnostics) => {
+			pdu.extend_from_slice(&0u16.to_be_bytes());
+			pdu.extend_from_slice(&address.to_be_bytes());
+		},
+		Some(MbFunc::ReadFifoQueue) => pdu.extend_from_slice(&address.to_be_bytes()),
+		Some(MbFunc::ReadExceptionStatus) | Some(MbFunc::GetCommEventCounter)
+		| Some(MbFunc::GetCommEventLog) | Some(MbFunc::ReportServerId) => {},
+		Some(MbFunc::EncapsulatedInterfaceTransport) => pdu.extend_from_slice(&[MEI_READ_DEVICE_ID, READ_BASIC, 0]),
+		_ => return Err(format!("функция 0x{:02X} не подходит для поиска устройств", function)),
+	}
+	Ok(pdu)
+}
+
+// Список адресов с объединением подряд идущих в диапазоны: "1-3, 7, 10-247"
+pub fn format_ids(ids: &[u8]) -> String {
+	let mut parts = Vec::new();
+	let mut i = 0;
+	while i < ids.len() {
+		let mut j = i;
+		while j + 1 < ids.len() && ids[j + 1] as u16 == ids[j] as u16 + 1 { j += 1; }
+		if j == i { parts.push(ids[i].to_string()); }
+		else { parts.push(format!("{}-{}", ids[i], ids[j])); }
+		i = j + 1;
+	}
+	parts.join(", ")
+}
+
+// Пробный запрос по каждому адресу из ids. on_result вызывается после каждого адреса.
+// Ошибка ввода-вывода прерывает поиск
+pub fn scan<T, F>(client: &mut Client<T>, ids: RangeInclusive<u8>, function: u8, address: u16, mut on_result: F)
+	-> Result<Vec<(u8, ScanResult)>, ClientError>
+	where T: Transport, F: FnMut(u8, &ScanResult)
+{
+	let pdu = probe_pdu(function, address).map_err(ClientError::InvalidRequest)?;
+	let gap = client.frame_gap();
+	let mut results = Vec::new();
+	for unit in ids {
+		let result = match client.transact(unit, &pdu, None) {
+			Ok(_) => ScanResult::Answered,
+			Err(ClientError::Exception { code, .. }) => ScanResult::Exception(code),
+			Err(ClientError::Timeout) => ScanResult::Silent,
+			Err(ClientError::Io(e)) => return Err(ClientError::Io(e)),
+			Err(e) => ScanResult::Invalid(e),
+		};
+		on_result(unit, &result);
+		results.push((unit, result));
+		// Пауза, чтобы опоздавший ответ не смешался со следующим
+		thread::sleep(gap);
+	}
+	Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::{ TcpListener, TcpStream };
+	use std::time::Duration;
+	use crate::server::{ Server, Framing };
+	use crate::server::device::{ Device, Units };
+
+	#[test]
+	fn probe_requests() {
+		assert_eq!(probe_pdu(0x03, 0x0010).unwrap(), vec![0x03, 0x00, 0x10, 0x00, 0x01]);
+		assert_eq!(probe_pdu(0x11, 0x0010).unwrap(), vec![0x11]);
+		assert_eq!(probe_pdu(0x2B, 0).unwrap(), vec![0x2B, 0x0E, 0x01, 0x00]);
+		assert!(probe_pdu(0x06, 0).is_err());
+	}
+
+	#[test]
+	fn id_ranges() {
+		assert_eq!(format_ids(&[]), "");
+		assert_eq!(format_ids(&[5]), "5");
+		assert_eq!(format_ids(&[1, 2, 3, 7, 10, 11, 255]), "1-3, 7, 10-11, 255");
+	}
+
+	#[test]
+	fn scan_units() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let mut with_fifo = Device::new();
+		with_fifo.add_fifo(10);
+		let mut units = Units::new();
+		units.insert(1, with_fifo.shared());
+		units.insert(3, Device::new().shared());
+		thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			Server::with_units(stream, units, Framing::Rtu).start().unwrap();
+		});
+		let mut client = Client::new(TcpStream::connect(addr).unwrap());
+		client.set_timeout(Duration::from_millis(50));
+
+		let mut seen = Vec::new();
+		let results = scan(&mut client, 1..=4, 0x18, 10, |unit, _| seen.push(unit)).unwrap();
+		assert_eq!(seen, vec![1, 2, 3, 4]);
+		assert!(matches!(results[0].1, ScanResult::Answered));
+		assert!(matches!(results[1].1, ScanResult::Silent));
+		assert!(matches!(results[2].1, ScanResult::Exception(2)));
+		assert!(matches!(results[3].1, ScanResult::Silent));
+	}
+}
@@ -0,0 +1,30 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Библиотека: сервер, клиент, монитор линии, обработка PDU, кадрирование и типы исключений
+//------------------------------------------------------------------------------
+extern crate num;
+#[macro_use]
+extern crate num_derive;
+
+pub mod server;
+pub mod client;
+pub mod monitor;
+pub mod transport;
+
+pub use server::{ Server, Framing, get_query_len };
+pub use server::device::{ Device, SharedDevice, Units };
+pub use server::store::{ DataStore, VecStore, BitTable, RegisterTable, Layout, TableLayout, TableMap };
+pub use server::identification::DeviceIdentification;
+pub use server::status::ServerId;
+pub use server::handler::{ FunctionHandler, QueryLen };
+pub use server::tcp::TcpServer;
+pub use client::{ Client, ClientError, get_response_len };
+pub use monitor::Monitor;
+pub use transport::{ Transport, MemoryTransport };
+pub use server::formal::{ crc, lrc, pack_bits, unpack_bits, ascii_encode, ascii_decode };
+pub use server::formal::{ MbFunc, MbExc, MbExcWithMessage };
@@ -6,58 +6,587 @@
 //------------------------------------------------------------------------------
 // Простой сервер Modbus RTU
 //------------------------------------------------------------------------------
-use std::path::PathBuf;
-use std::time::Duration;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::time::{ Duration, SystemTime };
+use std::thread;
 
 use structopt::StructOpt;
 use serialport::{ SerialPort, Parity };
 
-mod server;
-
-extern crate num;
-#[macro_use]
-extern crate num_derive;
+use modbus_uart::{ Server, Framing, Device, TcpServer, Units };
+use modbus_uart::{ VecStore, Layout, TableMap, DeviceIdentification, ServerId };
+use modbus_uart::server::identification::{ OBJ_VENDOR_NAME, OBJ_PRODUCT_CODE, OBJ_MAJOR_MINOR_REVISION };
+use modbus_uart::server::image::{ Image, exception_coils };
+use modbus_uart::server::state;
+use modbus_uart::{ Client, Monitor };
+use modbus_uart::monitor::trace_frame;
+use modbus_uart::client::FileRecordRequest;
+use modbus_uart::client::poll::{ PollList, Poller, PollItem, PollStatus, PollValue };
+use modbus_uart::client::scan::{ scan, format_ids, ScanResult };
+use modbus_uart::client::detect::detect;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "Modbus RTU", about = "parameters")]
 struct Opt {
-	/// Input file
+	/// Initial register image file (TOML, see src/server/image.rs)
 	#[structopt(parse(from_os_str), default_value="")]
 	ifile: PathBuf,
+	/// State file for coils, holding registers and file records, restored on start and rewritten after writes
+	#[structopt(long, parse(from_os_str))]
+	state: Option<PathBuf>,
+	/// Delay in ms between the last write and saving the state file
+	#[structopt(long, default_value="500")]
+	state_debounce: u64,
+	/// Discrete inputs table as comma-separated [START:]SIZE blocks (overrides the image file)
+	#[structopt(long)]
+	discrete_inputs: Option<TableMap>,
+	/// Coils table as comma-separated [START:]SIZE blocks (overrides the image file)
+	#[structopt(long)]
+	coils: Option<TableMap>,
+	/// Input registers table as comma-separated [START:]SIZE blocks (overrides the image file)
+	#[structopt(long)]
+	input_registers: Option<TableMap>,
+	/// Holding registers table as comma-separated [START:]SIZE blocks (overrides the image file)
+	#[structopt(long)]
+	holding_registers: Option<TableMap>,
+	/// Device identification VendorName (overrides the image file)
+	#[structopt(long)]
+	vendor_name: Option<String>,
+	/// Device identification ProductCode (overrides the image file)
+	#[structopt(long)]
+	product_code: Option<String>,
+	/// Device identification MajorMinorRevision (overrides the image file)
+	#[structopt(long)]
+	revision: Option<String>,
+	/// Server id string returned by Report Server ID (overrides the image file)
+	#[structopt(long)]
+	server_id: Option<String>,
+	/// Comma-separated coil addresses for bits 0-7 of Read Exception Status (overrides the image file)
+	#[structopt(long, use_delimiter = true)]
+	exception_coils: Option<Vec<u16>>,
+	/// Comma-separated FIFO pointer addresses for Read FIFO Queue (added to the image file ones)
+	#[structopt(long, use_delimiter = true)]
+	fifos: Vec<u16>,
 	/// Slave id
 	#[structopt(short, long, default_value="1")]
 	slave_id: u8,
+	/// Additional unit served on the same port, as ID or ID=IMAGE_FILE (may be repeated)
+	#[structopt(long = "unit", number_of_values = 1)]
+	units: Vec<UnitSpec>,
 	/// Serial port name
 	#[structopt(short, long)]
-	port: String,
+	port: Option<String>,
+	/// Modbus TCP listen address (e.g. 0.0.0.0:502)
+	#[structopt(long)]
+	tcp: Option<String>,
 	/// Baud rate
 	#[structopt(short, long, default_value="9600")]
 	baudrate: u32,
 	/// Serial port parity
 	#[structopt(short="a", long, default_value="even")]
 	parity: String,
+	/// Serial framing mode (rtu or ascii)
+	#[structopt(short, long, default_value="rtu")]
+	mode: String,
 	/// Timeout in ms
 	#[structopt(short, long, default_value="1000")]
 	timeout: u64,
+	/// Act as a master (send one request to the slave id (-s) and print the response) or monitor the line
+	#[structopt(subcommand)]
+	cmd: Option<Command>,
+}
+
+// Запросы в режиме мастера. Числа можно задавать в шестнадцатеричном виде (0x...)
+#[derive(Debug, StructOpt)]
+enum Command {
+	/// Read Coils (0x01)
+	ReadCoils {
+		#[structopt(parse(try_from_str = parse_u16))]
+		address: u16,
+		#[structopt(parse(try_from_str = parse_u16), default_value="1")]
+		quantity: u16,
+	},
+	/// Read Discrete Inputs (0x02)
+	ReadDiscrete {
+		#[structopt(parse(try_from_str = parse_u16))]
+		address: u16,
+		#[structopt(parse(try_from_str = parse_u16), default_value="1")]
+		quantity: u16,
+	},
+	/// Read Holding Registers (0x03)
+	ReadHolding {
+		#[structopt(parse(try_from_str = parse_u16))]
+		address: u16,
+		#[structopt(parse(try_from_str = parse_u16), default_value="1")]
+		quantity: u16,
+	},
+	/// Read Input Registers (0x04)
+	ReadInput {
+		#[structopt(parse(try_from_str = parse_u16))]
+		address: u16,
+		#[structopt(parse(try_from_str = parse_u16), default_value="1")]
+		quantity: u16,
+	},
+	/// Write Single Coil (0x05), value is 0/1 or off/on
+	WriteCoil {
+		#[structopt(parse(try_from_str = parse_u16))]
+		address: u16,
+		#[structopt(parse(try_from_str = parse_bool))]
+		value: bool,
+	},
+	/// Write Single Register (0x06)
+	WriteRegister {
+		#[structopt(parse(try_from_str = parse_u16))]
+		address: u16,
+		#[structopt(parse(try_from_str = parse_u16))]
+		value: u16,
+	},
+	/// Write Multiple Coils (0x0F), values are 0/1 or off/on
+	WriteCoils {
+		#[structopt(parse(try_from_str = parse_u16))]
+		address: u16,
+		#[structopt(parse(try_from_str = parse_bool), required = true)]
+		values: Vec<bool>,
+	},
+	/// Write Multiple Registers (0x10)
+	WriteRegisters {
+		#[structopt(parse(try_from_str = parse_u16))]
+		address: u16,
+		#[structopt(parse(try_from_str = parse_u16), required = true)]
+		values: Vec<u16>,
+	},
+	/// Mask Write Register (0x16)
+	MaskWrite {
+		#[structopt(parse(try_from_str = parse_u16))]
+		address: u16,
+		#[structopt(parse(try_from_str = parse_u16))]
+		and_mask: u16,
+		#[structopt(parse(try_from_str = parse_u16))]
+		or_mask: u16,
+	},
+	/// Read/Write Multiple Registers (0x17)
+	ReadWrite {
+		#[structopt(parse(try_from_str = parse_u16))]
+		read_address: u16,
+		#[structopt(parse(try_from_str = parse_u16))]
+		read_quantity: u16,
+		#[structopt(parse(try_from_str = parse_u16))]
+		write_address: u16,
+		#[structopt(parse(try_from_str = parse_u16), required = true)]
+		values: Vec<u16>,
+	},
+	/// Read Exception Status (0x07)
+	ExceptionStatus,
+	/// Diagnostics (0x08)
+	Diagnostics {
+		#[structopt(parse(try_from_str = parse_u16))]
+		sub_function: u16,
+		#[structopt(parse(try_from_str = parse_u16), default_value="0")]
+		data: u16,
+	},
+	/// Get Comm Event Counter (0x0B)
+	EventCounter,
+	/// Get Comm Event Log (0x0C)
+	EventLog,
+	/// Report Server ID (0x11)
+	ServerId,
+	/// Read FIFO Queue (0x18)
+	ReadFifo {
+		#[structopt(parse(try_from_str = parse_u16))]
+		address: u16,
+	},
+	/// Read File Record (0x14)
+	ReadFile {
+		#[structopt(parse(try_from_str = parse_u16))]
+		file: u16,
+		#[structopt(parse(try_from_str = parse_u16))]
+		record: u16,
+		#[structopt(parse(try_from_str = parse_u16), default_value="1")]
+		length: u16,
+	},
+	/// Write File Record (0x15)
+	WriteFile {
+		#[structopt(parse(try_from_str = parse_u16))]
+		file: u16,
+		#[structopt(parse(try_from_str = parse_u16))]
+		record: u16,
+		#[structopt(parse(try_from_str = parse_u16), required = true)]
+		values: Vec<u16>,
+	},
+	/// Read Device Identification (0x2B / 0x0E), code 1 - basic, 2 - regular, 3 - extended
+	DeviceId {
+		#[structopt(parse(try_from_str = parse_u8), default_value="1")]
+		code: u8,
+	},
+	/// Poll the blocks of a poll list (TOML, see src/client/poll.rs) cyclically, ignores -s
+	Poll {
+		#[structopt(parse(from_os_str))]
+		file: PathBuf,
+		/// Retries after a timeout or CRC error
+		#[structopt(long, default_value="2")]
+		retries: u32,
+	},
+	/// Probe unit ids on the line and report which of them answer, ignores -s
+	Scan {
+		/// Probe function: 0x01-0x04, 0x07, 0x08, 0x0B, 0x0C, 0x11, 0x18 or 0x2B
+		#[structopt(long, parse(try_from_str = parse_u8), default_value="0x03")]
+		function: u8,
+		/// Probe address (data for 0x08)
+		#[structopt(long, parse(try_from_str = parse_u16), default_value="0")]
+		address: u16,
+		/// First unit id
+		#[structopt(long, parse(try_from_str = parse_u8), default_value="1")]
+		first: u8,
+		/// Last unit id
+		#[structopt(long, parse(try_from_str = parse_u8), default_value="247")]
+		last: u8,
+		/// Response timeout in ms for each id (overrides -t)
+		#[structopt(long, default_value="100")]
+		probe_timeout: u64,
+	},
+	/// Find the baud rate, parity and stop bits of the slave id (-s), ignores -b and -a
+	Detect {
+		/// Probe function: 0x01-0x04, 0x07, 0x08, 0x0B, 0x0C, 0x11, 0x18 or 0x2B
+		#[structopt(long, parse(try_from_str = parse_u8), default_value="0x03")]
+		function: u8,
+		/// Probe address (data for 0x08)
+		#[structopt(long, parse(try_from_str = parse_u16), default_value="0")]
+		address: u16,
+		/// Response timeout in ms in addition to the frame transmission time (overrides -t)
+		#[structopt(long, default_value="200")]
+		probe_timeout: u64,
+	},
+	/// Listen to the line without transmitting and print a decoded trace of requests and responses
+	Monitor,
+}
+
+// Число в десятичном или шестнадцатеричном (0x...) виде
+fn parse_u16(s: &str) -> Result<u16, String> {
+	let r = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+		Some(hex) => u16::from_str_radix(hex, 16),
+		None      => s.parse::<u16>(),
+	};
+	r.map_err(|e| format!("неверное число \"{}\": {}", s, e))
+}
+
+fn parse_u8(s: &str) -> Result<u8, String> {
+	let v = parse_u16(s)?;
+	if v > 0xFF { return Err(format!("число \"{}\" больше 255", s)); }
+	Ok(v as u8)
+}
+
+fn parse_bool(s: &str) -> Result<bool, String> {
+	match s.to_lowercase().as_str() {
+		"1" | "on" | "true"   => Ok(true),
+		"0" | "off" | "false" => Ok(false),
+		_ => Err(format!("неверное значение \"{}\", используйте 0/1 или off/on", s)),
+	}
+}
+
+// Дополнительное устройство на линии: адрес и необязательный файл образа
+#[derive(Debug)]
+struct UnitSpec {
+	id:    u8,
+	image: PathBuf,
+}
+
+impl FromStr for UnitSpec {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<UnitSpec, String> {
+		let (id, image) = match s.split_once('=') {
+			Some((id, image)) => (id, PathBuf::from(image)),
+			None              => (s, PathBuf::new()),
+		};
+		let id = id.trim().parse::<u8>().map_err(|e| format!("неверный адрес устройства \"{}\": {}", id, e))?;
+		Ok(UnitSpec { id, image })
+	}
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>>  {
 	let opt = Opt::from_args();
+	if let Some(cmd) = &opt.cmd {
+		if let Err(e) = run_client(&opt, cmd) {
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+		return Ok(());
+	}
+	let units = match build_units(&opt) {
+		Ok(u) => u,
+		Err(e) => {
+			eprintln!("{}", e);
+			std::process::exit(1);
+		},
+	};
+	if let Some(path) = &opt.state {
+		for (&id, device) in units.iter() {
+			let path = state_path(path, id, units.len() > 1);
+			state::spawn_state_writer(device.clone(), path, Duration::from_millis(opt.state_debounce));
+		}
+	}
+
+	let tcp_server = match &opt.tcp {
+		Some(addr) => Some(TcpServer::bind_units(addr.as_str(), units.clone())?),
+		None       => None,
+	};
+	let opt_port = match (&opt.port, tcp_server) {
+		(Some(p), Some(s)) => {
+			println!("Modbus TCP: {}", s.local_addr()?);
+			thread::spawn(move || {
+				if let Err(e) = s.start() { eprintln!("Сервер TCP остановлен: {}", e); }
+			});
+			p
+		},
+		(Some(p), None) => p,
+		(None, Some(s)) => {
+			println!("Modbus TCP: {}", s.local_addr()?);
+			s.start()?;
+			return Ok(());
+		},
+		(None, None) => {
+			eprintln!("Укажите последовательный порт (--port) и/или адрес Modbus TCP (--tcp)");
+			return Ok(());
+		},
+	};
+
+	let framing = match opt.mode.to_lowercase().as_str() {
+		"rtu"   => Framing::Rtu,
+		"ascii" => Framing::Ascii,
+		&_      => panic!("Неверно указан режим. Используйте значения: RTU и ASCII.")
+	};
+
+	let port = open_port(&opt, opt_port);
+
+	let mut server = Server::with_units(port, units, framing);
+	server.start()?;
 	
+	Ok(())
+}
+
+// Режим мастера (один запрос к устройству -s и вывод ответа) и монитора линии
+fn run_client(opt: &Opt, cmd: &Command) -> Result<(), Box<dyn std::error::Error>> {
+	let opt_port = opt.port.as_ref().ok_or("Укажите последовательный порт (--port)")?;
+	if opt.mode.to_lowercase() != "rtu" {
+		return Err("Режимы мастера и монитора поддерживают только RTU".into());
+	}
+	let mut client = Client::new(open_port(opt, opt_port));
+	client.set_timeout(Duration::from_millis(opt.timeout));
+	client.set_trace(|kind, frame, request| println!("{}", trace_frame(SystemTime::now(), kind, frame, request)));
+	let unit = opt.slave_id;
+
+	match *cmd {
+		Command::ReadCoils { address, quantity } => print_bits(address, &client.read_coils(unit, address, quantity)?),
+		Command::ReadDiscrete { address, quantity } => print_bits(address, &client.read_discrete_inputs(unit, address, quantity)?),
+		Command::ReadHolding { address, quantity } => print_registers(address, &client.read_holding_registers(unit, address, quantity)?),
+		Command::ReadInput { address, quantity } => print_registers(address, &client.read_input_registers(unit, address, quantity)?),
+		Command::WriteCoil { address, value } => client.write_single_coil(unit, address, value)?,
+		Command::WriteRegister { address, value } => client.write_single_register(unit, address, value)?,
+		Command::WriteCoils { address, ref values } => client.write_multiple_coils(unit, address, values)?,
+		Command::WriteRegisters { address, ref values } => client.write_multiple_registers(unit, address, values)?,
+		Command::MaskWrite { address, and_mask, or_mask } => client.mask_write_register(unit, address, and_mask, or_mask)?,
+		Command::ReadWrite { read_address, read_quantity, write_address, ref values } =>
+			print_registers(read_address, &client.read_write_multiple_registers(unit, read_address, read_quantity, write_address, values)?),
+		Command::ExceptionStatus => println!("{:08b}", client.read_exception_status(unit)?),
+		Command::Diagnostics { sub_function, data } => {
+			let data = client.diagnostics(unit, sub_function, data)?;
+			println!("{} (0x{:04X})", data, data);
+		},
+		Command::EventCounter => {
+			let counter = client.comm_event_counter(unit)?;
+			println!("status:      0x{:04X}", counter.status);
+			println!("event count: {}", counter.event_count);
+		},
+		Command::EventLog => {
+			let log = client.comm_event_log(unit)?;
+			println!("status:        0x{:04X}", log.status);
+			println!("event count:   {}", log.event_count);
+			println!("message count: {}", log.message_count);
+			println!("events:        {:02X?}", log.events);
+		},
+		Command::ServerId => {
+			let data = client.report_server_id(unit)?;
+			println!("{:02X?} \"{}\"", data, String::from_utf8_lossy(&data));
+		},
+		Command::ReadFifo { address } => print_registers(0, &client.read_fifo_queue(unit, address)?),
+		Command::ReadFile { file, record, length } => {
+			let records = client.read_file_record(unit, &[FileRecordRequest { file, record, length }])?;
+			print_registers(record, &records[0]);
+		},
+		Command::WriteFile { file, record, ref values } => client.write_file_record(unit, &[(file, record, values.as_slice())])?,
+		Command::DeviceId { code } => {
+			for (id, value) in client.read_all_device_identification(unit, code)? {
+				println!("0x{:02X}: \"{}\"", id, String::from_utf8_lossy(&value));
+			}
+		},
+		Command::Poll { ref file, retries } => {
+			let list = PollList::load(file).map_err(|e| format!("Не удалось загрузить список опроса \"{}\": {}", file.display(), e))?;
+			let mut poller = Poller::new(client, list.item)?;
+			poller.set_retries(retries);
+			poller.run(print_poll_status);
+		},
+		Command::Scan { function, address, first, last, probe_timeout } => {
+			client.set_timeout(Duration::from_millis(probe_timeout));
+			let results = scan(&mut client, first..=last, function, address, |unit, result| match result {
+				ScanResult::Answered     => println!("{}: ответ", unit),
+				ScanResult::Exception(c) => println!("{}: исключение {}", unit, c),
+				ScanResult::Silent       => println!("{}: нет ответа", unit),
+				ScanResult::Invalid(e)   => println!("{}: {}", unit, e),
+			})?;
+			let ids = |f: fn(&ScanResult) -> bool| -> Vec<u8> {
+				results.iter().filter(|(_, r)| f(r)).map(|(unit, _)| *unit).collect()
+			};
+			println!("================[ Scan ]=========================");
+			println!("answered:  {}", format_ids(&ids(|r| matches!(r, ScanResult::Answered))));
+			println!("exception: {}", format_ids(&ids(|r| matches!(r, ScanResult::Exception(_)))));
+			println!("invalid:   {}", format_ids(&ids(|r| matches!(r, ScanResult::Invalid(_)))));
+			println!("silent:    {}", format_ids(&ids(|r| matches!(r, ScanResult::Silent))));
+			println!("=================================================");
+		},
+		Command::Detect { function, address, probe_timeout } => {
+			let found = detect(&mut client, unit, function, address, Duration::from_millis(probe_timeout), |settings, result| {
+				match result {
+					Ok(_)  => println!("{}: ответ", settings),
+					Err(e) => println!("{}: {}", settings, e),
+				}
+			})?;
+			match found {
+				Some(settings) => println!("Параметры линии: {}", settings),
+				None           => return Err(format!("Устройство {} не ответило ни на одной скорости", unit).into()),
+			}
+		},
+		Command::Monitor => Monitor::new(client.into_transport()).run()?,
+	}
+	Ok(())
+}
+
+fn print_poll_status(item: &PollItem, status: &PollStatus) {
+	match (&status.error, &status.value) {
+		(None, Some(PollValue::Bits(bits))) =>
+			println!("{}: {:?}", item, bits.iter().map(|&b| b as u8).collect::<Vec<u8>>()),
+		(None, Some(PollValue::Registers(values))) => println!("{}: {:?}", item, values),
+		(Some(e), _) => match status.age() {
+			Some(age) => println!("{}: {}, значение получено {} мс назад", item, e, age.as_millis()),
+			None      => println!("{}: {}, значение не получено", item, e),
+		},
+		(None, None) => {},
+	}
+}
+
+fn print_bits(address: u16, bits: &[bool]) {
+	for (i, &b) in bits.iter().enumerate() {
+		println!("{}: {}", address as usize + i, b as u8);
+	}
+}
+
+fn print_registers(address: u16, values: &[u16]) {
+	for (i, v) in values.iter().enumerate() {
+		println!("{}: {} (0x{:04X})", address as usize + i, v, v);
+	}
+}
+
+// Создание всех устройств линии: основного (slave id и файл образа из позиционного
+// аргумента) и дополнительных (--unit)
+fn build_units(opt: &Opt) -> Result<Units, String> {
+	let mut specs = vec![UnitSpec { id: opt.slave_id, image: opt.ifile.clone() }];
+	specs.extend(opt.units.iter().map(|u| UnitSpec { id: u.id, image: u.image.clone() }));
+	let multiple = specs.len() > 1;
+
+	let mut units = Units::new();
+	for spec in specs.iter() {
+		if spec.id == 0 {
+			return Err("Адрес 0 зарезервирован для широковещательных запросов".into());
+		}
+		if units.contains_key(&spec.id) {
+			return Err(format!("Устройство с адресом {} указано несколько раз", spec.id));
+		}
+		let state = opt.state.as_ref().map(|p| state_path(p, spec.id, multiple));
+		let device = build_device(opt, &spec.image, state.as_deref())
+			.map_err(|e| format!("Устройство {}: {}", spec.id, e))?;
+		units.insert(spec.id, device.shared());
+	}
+	Ok(units)
+}
+
+// Файл состояния устройства. При нескольких устройствах к имени добавляется адрес:
+// state.toml -> state.2.toml
+fn state_path(base: &Path, id: u8, multiple: bool) -> PathBuf {
+	if !multiple { return base.to_path_buf(); }
+	let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+	let name = match base.extension() {
+		Some(ext) => format!("{}.{}.{}", stem, id, ext.to_string_lossy()),
+		None      => format!("{}.{}", stem, id),
+	};
+	base.with_file_name(name)
+}
+
+// Создание устройства: размещение таблиц, начальный образ и сохранённое состояние
+fn build_device(opt: &Opt, ifile: &Path, state: Option<&Path>) -> Result<Device, String> {
+	let image = if ifile.as_os_str().is_empty() { Image::default() } else {
+		Image::load(ifile).map_err(|e| format!("Не удалось загрузить образ \"{}\": {}", ifile.display(), e))?
+	};
+	let mut layout = image.layout(Layout::default())
+		.map_err(|e| format!("Неверное размещение таблиц в \"{}\": {}", ifile.display(), e))?;
+	if let Some(l) = &opt.discrete_inputs   { layout.discrete_inputs = l.clone(); }
+	if let Some(l) = &opt.coils             { layout.coils = l.clone(); }
+	if let Some(l) = &opt.input_registers   { layout.input_registers = l.clone(); }
+	if let Some(l) = &opt.holding_registers { layout.holding_registers = l.clone(); }
+
+	let mut identification = image.identification(DeviceIdentification::default())
+		.map_err(|e| format!("Неверная идентификация устройства в \"{}\": {}", ifile.display(), e))?;
+	let objects = [
+		(OBJ_VENDOR_NAME, &opt.vendor_name),
+		(OBJ_PRODUCT_CODE, &opt.product_code),
+		(OBJ_MAJOR_MINOR_REVISION, &opt.revision),
+	];
+	for (object, value) in objects {
+		if let Some(v) = value {
+			identification.set(object, v.as_bytes()).map_err(|e| format!("Неверная идентификация устройства: {}", e))?;
+		}
+	}
+
+	let mut server_id = image.server_id(ServerId::default())
+		.map_err(|e| format!("Неверный идентификатор сервера в \"{}\": {}", ifile.display(), e))?;
+	if let Some(id) = &opt.server_id {
+		server_id = ServerId::new(id.as_bytes(), server_id.run).map_err(|e| format!("Неверный идентификатор сервера: {}", e))?;
+	}
+	let mut coils = image.exception_coils()
+		.map_err(|e| format!("Неверное состояние исключений в \"{}\": {}", ifile.display(), e))?;
+	if let Some(c) = &opt.exception_coils {
+		coils = Some(exception_coils(c).map_err(|e| format!("Неверное состояние исключений: {}", e))?);
+	}
+
+	let mut device = Device::with_store(Box::new(VecStore::with_layout(&layout)));
+	device.set_identification(identification);
+	device.set_server_id(server_id);
+	if let Some(c) = coils { device.set_exception_coils(c); }
+	for &address in opt.fifos.iter() { device.add_fifo(address); }
+	device.apply_image(&image)
+		.map_err(|e| format!("Не удалось загрузить образ \"{}\": {}", ifile.display(), e))?;
+	if let Some(path) = state {
+		device.restore_state(path)
+			.map_err(|e| format!("Не удалось загрузить состояние \"{}\": {}", path.display(), e))?;
+	}
+	Ok(device)
+}
+
+// Открытие последовательного порта с параметрами из командной строки
+fn open_port(opt: &Opt, opt_port: &str) -> Box<dyn SerialPort> {
 	let ports = serialport::available_ports().expect("В системе не обнаружено последовательных портов");
 
-	let port_name = match ports.iter().find(|p| p.port_name == opt.port) {
+	let port_name = match ports.iter().find(|p| p.port_name == opt_port) {
 		Some(p) => p.port_name.as_str(),
 		None    => {
-			eprintln!("Внимание! Последовательный порт \"{}\" не найден.", opt.port);
+			eprintln!("Внимание! Последовательный порт \"{}\" не найден.", opt_port);
 			eprintln!("Список существующих:");
-			if ports.len() > 0 {
+			if !ports.is_empty() {
 				for (i, p) in ports.iter().enumerate() {
 					eprintln!("\t{}: {}", i, p.port_name);
 				}
 			}
 			else { eprintln!("[портов не найдено]"); }
-			opt.port.as_str()
+			opt_port
 		},
 	};
 	let parity = match opt.parity.to_lowercase().as_str() {
@@ -72,15 +601,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>>  {
 		.parity(parity)
 		.open().expect("Не удалось открыть порт");
 
-	display_port_settings(&port);
-
-	let mut server = server::Server::new(port, opt.slave_id);
-	server.start()?;
-	
-	Ok(())
+	display_port_settings(&*port);
+	port
 }
 
-fn display_port_settings(port: &Box<dyn SerialPort>) {
+fn display_port_settings(port: &dyn SerialPort) {
 	println!("================[ Serial port ]==================");
 	println!("name:         {:?}", port.name().unwrap());
 	println!("baud rate:    {:?}", port.baud_rate().unwrap());
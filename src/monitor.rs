@@ -0,0 +1,435 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Пассивный монитор линии: разделение трафика на кадры RTU по паузе
+// в 3.5 символа, разбор запросов и ответов, трассировка с временем
+//------------------------------------------------------------------------------
+use std::io;
+use std::io::ErrorKind;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use byteorder::{ ByteOrder, LittleEndian };
+
+use crate::client::get_response_len;
+use crate::server::{ get_query_len, IN_BUF_SIZE };
+use crate::server::formal::*;
+use crate::transport::{ Transport, MIN_FRAME_GAP };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+	Request,
+	Response,
+}
+
+// Время суток UTC с миллисекундами
+pub fn format_time(time: SystemTime) -> String {
+	let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+	let secs = since.as_secs() % 86400;
+	format!("{:02}:{:02}:{:02}.{:03}", secs / 3600, secs / 60 % 60, secs % 60, since.subsec_millis())
+}
+
+fn hex(data: &[u8]) -> String {
+	data.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(" ")
+}
+
+fn function_name(function: u8) -> String {
+	let f: Option<MbFunc> = num::FromPrimitive::from_u8(function & 0x7F);
+	match f {
+		Some(f) => format!("{:?}", f),
+		None    => format!("0x{:02X}", function & 0x7F),
+	}
+}
+
+fn word(data: &[u8], i: usize) -> Option<u16> {
+	Some(u16::from_be_bytes([*data.get(i)?, *data.get(i + 1)?]))
+}
+
+fn words(data: &[u8]) -> Option<Vec<u16>> {
+	if data.len() % 2 != 0 { return None; }
+	Some(data.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect())
+}
+
+// Данные длины n
+fn exact(data: &[u8], n: usize) -> Option<&[u8]> {
+	if data.len() == n { Some(data) } else { None }
+}
+
+// Биты в порядке адресов, не более count
+fn bits(data: &[u8], count: usize) -> String {
+	let mut unpacked = vec![0u8; count.min(data.len() * 8)];
+	unpack_bits(data, &mut unpacked);
+	unpacked.iter().map(|&b| if b != 0 { '1' } else { '0' }).collect()
+}
+
+// Данные после счётчика байт в позиции 0
+fn counted(data: &[u8]) -> Option<&[u8]> {
+	let (&count, rest) = data.split_first()?;
+	exact(rest, count as usize)
+}
+
+fn address_value(data: &[u8]) -> Option<String> {
+	let d = exact(data, 4)?;
+	Some(format!("адрес {}, значение {}", word(d, 0)?, word(d, 2)?))
+}
+
+fn address_quantity(data: &[u8]) -> Option<String> {
+	let d = exact(data, 4)?;
+	Some(format!("адрес {}, количество {}", word(d, 0)?, word(d, 2)?))
+}
+
+fn write_coil(data: &[u8]) -> Option<String> {
+	let d = exact(data, 4)?;
+	let value = match word(d, 2)? {
+		0xFF00 => "ON".to_string(),
+		0x0000 => "OFF".to_string(),
+		v      => format!("0x{:04X}", v),
+	};
+	Some(format!("адрес {}, значение {}", word(d, 0)?, value))
+}
+
+fn diagnostics(data: &[u8]) -> Option<String> {
+	let sub_function = word(data, 0)?;
+	Some(format!("подфункция {}, данные [{}]", sub_function, hex(&data[2..])))
+}
+
+fn mask_write(data: &[u8]) -> Option<String> {
+	let d = exact(data, 6)?;
+	Some(format!("адрес {}, AND 0x{:04X}, OR 0x{:04X}", word(d, 0)?, word(d, 2)?, word(d, 4)?))
+}
+
+fn write_file_record(data: &[u8]) -> Option<String> {
+	let mut d = counted(data)?;
+	let mut subs = Vec::new();
+	while !d.is_empty() {
+		let len = word(d, 5)? as usize;
+		let values = words(d.get(7..7 + len * 2)?)?;
+		subs.push(format!("файл {}, запись {}, значения {:?}", word(d, 1)?, word(d, 3)?, values));
+		d = &d[7 + len * 2..];
+	}
+	Some(subs.join("; "))
+}
+
+// Описание запроса: данные после кода функции
+pub fn describe_request(function: u8, data: &[u8]) -> String {
+	let f: Option<MbFunc> = num::FromPrimitive::from_u8(function);
+	let text = match f {
+		Some(MbFunc::ReadCoils) | Some(MbFunc::ReadDiscreteInputs)
+		| Some(MbFunc::ReadHoldingRegisters) | Some(MbFunc::ReadInputRegisters) => address_quantity(data),
+		Some(MbFunc::WriteSingleCoil) => write_coil(data),
+		Some(MbFunc::WriteSingleRegister) => address_value(data),
+		Some(MbFunc::WriteMultipleCoils) => (|| {
+			let quantity = word(data, 2)?;
+			let values = counted(data.get(4..)?)?;
+			Some(format!("адрес {}, количество {}, биты {}", word(data, 0)?, quantity, bits(values, quantity as usize)))
+		})(),
+		Some(MbFunc::WriteMultipleRegisters) => (|| {
+			let values = words(counted(data.get(4..)?)?)?;
+			Some(format!("адрес {}, значения {:?}", word(data, 0)?, values))
+		})(),
+		Some(MbFunc::ReadExceptionStatus) | Some(MbFunc::GetCommEventCounter)
+		| Some(MbFunc::GetCommEventLog) | Some(MbFunc::ReportServerId) => exact(data, 0).map(|_| String::new()),
+		Some(MbFunc::Diagnostics) => diagnostics(data),
+		Some(MbFunc::ReadFileRecord) => (|| {
+			let d = counted(data)?;
+			if d.len() % 7 != 0 { return None; }
+			let subs: Vec<String> = d.chunks(7)
+				.map(|s| format!("файл {}, запись {}, длина {}", u16::from_be_bytes([s[1], s[2]]), u16::from_be_bytes([s[3], s[4]]), u16::from_be_bytes([s[5], s[6]])))
+				.collect();
+			Some(subs.join("; "))
+		})(),
+		Some(MbFunc::WriteFileRecord) => write_file_record(data),
+		Some(MbFunc::MaskWriteRegister) => mask_write(data),
+		Some(MbFunc::ReadWriteMultipleRegisters) => (|| {
+			let values = words(counted(data.get(8..)?)?)?;
+			Some(format!("чтение: адрес {}, количество {}; запись: адрес {}, значения {:?}",
+				word(data, 0)?, word(data, 2)?, word(data, 4)?, values))
+		})(),
+		Some(MbFunc::ReadFifoQueue) => exact(data, 2).and_then(|d| Some(format!("адрес указателя {}", word(d, 0)?))),
+		Some(MbFunc::EncapsulatedInterfaceTransport) => exact(data, 3)
+			.map(|d| format!("MEI 0x{:02X}, код {}, объект 0x{:02X}", d[0], d[1], d[2])),
+		None => None,
+	};
+	text.unwrap_or_else(|| format!("данные [{}]", hex(data)))
+}
+
+// Описание ответа: данные после кода функции.
+// request - данные запроса после кода функции, если запрос известен
+pub fn describe_response(function: u8, data: &[u8], request: Option<&[u8]>) -> String {
+	if function & 0x80 != 0 {
+		return match data {
+			[code] => {
+				let exc: Option<MbExc> = num::FromPrimitive::from_u8(*code);
+				match exc {
+					Some(e) => format!("исключение {:?} ({})", e, code),
+					None    => format!("исключение {}", code),
+				}
+			},
+			_ => format!("исключение, данные [{}]", hex(data)),
+		};
+	}
+	let f: Option<MbFunc> = num::FromPrimitive::from_u8(function);
+	let text = match f {
+		Some(MbFunc::ReadCoils) | Some(MbFunc::ReadDiscreteInputs) => counted(data).map(|d| {
+			// Количество бит известно только из запроса
+			let count = request.and_then(|r| word(r, 2)).map(|q| q as usize).unwrap_or(d.len() * 8);
+			format!("биты {}", bits(d, count))
+		}),
+		Some(MbFunc::ReadHoldingRegisters) | Some(MbFunc::ReadInputRegisters)
+		| Some(MbFunc::ReadWriteMultipleRegisters) => counted(data).and_then(words).map(|v| format!("значения {:?}", v)),
+		Some(MbFunc::WriteSingleCoil) => write_coil(data),
+		Some(MbFunc::WriteSingleRegister) => address_value(data),
+		Some(MbFunc::WriteMultipleCoils) | Some(MbFunc::WriteMultipleRegisters) => address_quantity(data),
+		Some(MbFunc::ReadExceptionStatus) => exact(data, 1).map(|d| format!("состояние {:08b}", d[0])),
+		Some(MbFunc::Diagnostics) => diagnostics(data),
+		Some(MbFunc::GetCommEventCounter) => exact(data, 4)
+			.and_then(|d| Some(format!("состояние 0x{:04X}, событий {}", word(d, 0)?, word(d, 2)?))),
+		Some(MbFunc::GetCommEventLog) => counted(data).and_then(|d| {
+			Some(format!("состояние 0x{:04X}, событий {}, сообщений {}, журнал [{}]",
+				word(d, 0)?, word(d, 2)?, word(d, 4)?, hex(d.get(6..)?)))
+		}),
+		Some(MbFunc::ReportServerId) => counted(data)
+			.map(|d| format!("данные [{}] \"{}\"", hex(d), String::from_utf8_lossy(d))),
+		Some(MbFunc::ReadFileRecord) => counted(data).and_then(|mut d| {
+			let mut files = Vec::new();
+			while !d.is_empty() {
+				let len = d[0] as usize;
+				files.push(format!("{:?}", words(d.get(2..1 + len)?)?));
+				d = &d[1 + len..];
+			}
+			Some(format!("записи {}", files.join("; ")))
+		}),
+		Some(MbFunc::WriteFileRecord) => write_file_record(data),
+		Some(MbFunc::MaskWriteRegister) => mask_write(data),
+		Some(MbFunc::ReadFifoQueue) => (|| {
+			let count = word(data, 2)? as usize;
+			let values = words(data.get(4..)?)?;
+			if word(data, 0)? as usize != 2 + count * 2 || values.len() != count { return None; }
+			Some(format!("значения {:?}", values))
+		})(),
+		Some(MbFunc::EncapsulatedInterfaceTransport) => (|| {
+			let mut d = data.get(6..)?;
+			let mut objects = Vec::new();
+			for _ in 0..*data.get(5)? {
+				let len = *d.get(1)? as usize;
+				objects.push(format!("0x{:02X}=\"{}\"", d[0], String::from_utf8_lossy(d.get(2..2 + len)?)));
+				d = &d[2 + len..];
+			}
+			let mut text = format!("код {}, соответствие 0x{:02X}, объекты {}", data[1], data[2], objects.join(", "));
+			if data[3] == 0xFF { text.push_str(&format!(", продолжение с 0x{:02X}", data[4])); }
+			Some(text)
+		})(),
+		None => None,
+	};
+	text.unwrap_or_else(|| format!("данные [{}]", hex(data)))
+}
+
+fn arrow(kind: FrameKind) -> &'static str {
+	match kind {
+		FrameKind::Request  => "->",
+		FrameKind::Response => "<-",
+	}
+}
+
+// Время, направление, адрес устройства и функция
+fn trace_header(time: SystemTime, kind: FrameKind, unit: u8, function: u8) -> String {
+	let unit = if unit == BROADCAST_ID { "*".to_string() } else { unit.to_string() };
+	format!("{} {} {} {}", format_time(time), arrow(kind), unit, function_name(function))
+}
+
+// Строка трассировки кадра без контрольной суммы (например, декодированного кадра ASCII).
+// request - запрос, на который отвечает кадр
+pub fn trace_adu(time: SystemTime, kind: FrameKind, adu: &[u8], request: Option<&[u8]>) -> String {
+	if adu.len() < 2 {
+		return format!("{} {} короткий кадр [{}]", format_time(time), arrow(kind), hex(adu));
+	}
+	let mut line = trace_header(time, kind, adu[0], adu[1]);
+	let description = match kind {
+		FrameKind::Request  => describe_request(adu[1], &adu[2..]),
+		FrameKind::Response => describe_response(adu[1], &adu[2..], request.and_then(|r| r.get(2..))),
+	};
+	if !description.is_empty() { line.push_str(&format!(": {}", description)); }
+	line
+}
+
+// Строка трассировки кадра RTU, включая адрес устройства и CRC.
+// request - запрос без CRC, на который отвечает кадр
+pub fn trace_frame(time: SystemTime, kind: FrameKind, frame: &[u8], request: Option<&[u8]>) -> String {
+	if frame.len() < 4 {
+		return format!("{} {} короткий кадр [{}]", format_time(time), arrow(kind), hex(frame));
+	}
+	let adu = &frame[..frame.len() - 2];
+	let mut line;
+	if LittleEndian::read_u16(&frame[frame.len() - 2..]) != crc(adu) {
+		line = trace_header(time, kind, adu[0], adu[1]);
+		line.push_str(": ОШИБКА CRC");
+	}
+	else {
+		line = trace_adu(time, kind, adu, request);
+		let expected = match kind {
+			FrameKind::Request  => get_query_len(frame).ok(),
+			FrameKind::Response => get_response_len(frame).ok(),
+		};
+		if let Some(len) = expected.filter(|&l| l != frame.len() && l != usize::MAX) {
+			line.push_str(&format!(" (длина {} вместо {})", frame.len(), len));
+		}
+	}
+	line.push_str(&format!(" [{}]", hex(frame)));
+	line
+}
+
+// Длина первого кадра в bytes, если за ним следует ещё один
+// (пауза между кадрами не была замечена)
+fn split_len(bytes: &[u8]) -> Option<usize> {
+	let lens = [get_query_len(bytes).ok(), get_response_len(bytes).ok()];
+	lens.iter().flatten().copied().find(|&len| {
+		len >= 4 && len < bytes.len() && LittleEndian::read_u16(&bytes[len - 2..len]) == crc(&bytes[..len - 2])
+	})
+}
+
+pub struct Monitor<T: Transport> {
+	port:    T,
+	gap:     Duration,
+	chunk:   Vec<u8>,
+	// Запрос без CRC, ожидающий ответа, и время его окончания
+	pending: Option<(Vec<u8>, Instant)>,
+}
+
+impl<T: Transport> Monitor<T> {
+	pub fn new(port: T) -> Monitor<T> {
+		// 3.5 символа, но не меньше 1.75 мс
+		let gap = port.char_time()
+			.map(|t| (t * 7 / 2).max(MIN_FRAME_GAP))
+			.unwrap_or(MIN_FRAME_GAP);
+		Monitor { port, gap, chunk: vec![0; IN_BUF_SIZE], pending: None }
+	}
+
+	// Пауза, разделяющая кадры
+	pub fn set_gap(&mut self, gap: Duration) {
+		self.gap = gap;
+	}
+
+	// Чтение до паузы на линии. Возвращает время первого байта и принятые байты,
+	// None - транспорт закрыт
+	pub fn next_frame(&mut self) -> io::Result<Option<(SystemTime, Vec<u8>)>> {
+		self.port.set_timeout(self.gap)?;
+		let mut frame = Vec::new();
+		let mut time = SystemTime::now();
+		loop {
+			match self.port.read(&mut self.chunk) {
+				Ok(0) => return Ok(if frame.is_empty() { None } else { Some((time, frame)) }),
+				Ok(n) => {
+					if frame.is_empty() { time = SystemTime::now(); }
+					frame.extend_from_slice(&self.chunk[..n]);
+				},
+				Err(ref e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => {
+					if !frame.is_empty() { return Ok(Some((time, frame))); }
+				},
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	// Трассировка принятых байтов: кадр, начинающийся с того же адреса и кода функции,
+	// что и предыдущий запрос, считается ответом на него. Слитные кадры разделяются
+	pub fn trace(&mut self, time: SystemTime, bytes: &[u8]) -> Vec<String> {
+		let mut lines = Vec::new();
+		let mut rest = bytes;
+		while !rest.is_empty() {
+			let len = split_len(rest).unwrap_or(rest.len());
+			let (frame, tail) = rest.split_at(len);
+			lines.push(self.trace_one(time, frame));
+			rest = tail;
+		}
+		lines
+	}
+
+	fn trace_one(&mut self, time: SystemTime, frame: &[u8]) -> String {
+		let response_to = match &self.pending {
+			Some((request, _)) if frame.len() >= 2 && frame[0] == request[0]
+				&& (frame[1] == request[1] || frame[1] == request[1] | 0x80)
+				&& get_response_len(frame).ok() == Some(frame.len()) => self.pending.take(),
+			_ => None,
+		};
+		match response_to {
+			Some((request, sent)) => {
+				let line = trace_frame(time, FrameKind::Response, frame, Some(&request));
+				format!("{} (+{} мс)", line, sent.elapsed().as_millis())
+			},
+			None => {
+				// На широковещательный запрос ответа нет
+				self.pending = match frame {
+					[unit, ..] if *unit != BROADCAST_ID && frame.len() >= 4 => Some((frame[..frame.len() - 2].to_vec(), Instant::now())),
+					_ => None,
+				};
+				trace_frame(time, FrameKind::Request, frame, None)
+			},
+		}
+	}
+
+	// Вывод трассировки до закрытия транспорта. В линию ничего не передаётся
+	pub fn run(&mut self) -> io::Result<()> {
+		while let Some((time, bytes)) = self.next_frame()? {
+			for line in self.trace(time, &bytes) {
+				println!("{}", line);
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::transport::MemoryTransport;
+
+	fn rtu(adu: &[u8]) -> Vec<u8> {
+		let mut frame = adu.to_vec();
+		frame.extend_from_slice(&crc(adu).to_le_bytes());
+		frame
+	}
+
+	#[test]
+	fn describe_frames() {
+		assert_eq!(describe_request(0x03, &[0x00, 0x10, 0x00, 0x02]), "адрес 16, количество 2");
+		assert_eq!(describe_request(0x05, &[0x00, 0x01, 0xFF, 0x00]), "адрес 1, значение ON");
+		assert_eq!(describe_request(0x0F, &[0x00, 0x00, 0x00, 0x03, 0x01, 0x05]), "адрес 0, количество 3, биты 101");
+		assert_eq!(describe_request(0x03, &[0x00]), "данные [00]");
+		assert_eq!(describe_response(0x01, &[0x01, 0x05], Some(&[0x00, 0x00, 0x00, 0x04])), "биты 1010");
+		assert_eq!(describe_response(0x03, &[0x04, 0x12, 0x34, 0x00, 0x01], None), "значения [4660, 1]");
+		assert_eq!(describe_response(0x83, &[0x02], None), "исключение IllegalDataAddress (2)");
+		assert_eq!(
+			describe_response(0x2B, &[0x0E, 0x01, 0x81, 0x00, 0x00, 0x01, 0x00, 0x02, b'A', b'B'], None),
+			"код 1, соответствие 0x81, объекты 0x00=\"AB\""
+		);
+	}
+
+	#[test]
+	fn monitor_trace() {
+		let mut transport = MemoryTransport::new();
+		transport.push_frame(&rtu(&[1, 0x03, 0x00, 0x00, 0x00, 0x01]));
+		transport.push_frame(&rtu(&[1, 0x03, 0x02, 0x00, 0x07]));
+		let mut corrupted = rtu(&[2, 0x06, 0x00, 0x01, 0x00, 0x07]);
+		corrupted[7] ^= 0xFF;
+		transport.push_frame(&corrupted);
+		// Запрос и ответ-исключение без паузы между ними
+		let mut merged = rtu(&[3, 0x04, 0x10, 0x00, 0x00, 0x01]);
+		merged.extend_from_slice(&rtu(&[3, 0x84, 0x02]));
+		transport.push_frame(&merged);
+
+		let mut monitor = Monitor::new(transport);
+		let mut lines = Vec::new();
+		while let Some((time, bytes)) = monitor.next_frame().unwrap() {
+			lines.extend(monitor.trace(time, &bytes));
+		}
+		assert_eq!(lines.len(), 5);
+		assert!(lines[0].contains("-> 1 ReadHoldingRegisters: адрес 0, количество 1"));
+		assert!(lines[1].contains("<- 1 ReadHoldingRegisters: значения [7]"));
+		assert!(lines[2].contains("-> 2 WriteSingleRegister: ОШИБКА CRC"));
+		assert!(lines[3].contains("-> 3 ReadInputRegisters: адрес 4096, количество 1"));
+		assert!(lines[4].contains("<- 3 ReadInputRegisters: исключение IllegalDataAddress (2)"));
+	}
+}
@@ -7,30 +7,51 @@
 // Простой сервер Modbus RTU
 // Структура сервера
 //------------------------------------------------------------------------------
-use std::io::Write;
-use std::time::Duration;
+use std::time::{ Duration, Instant, SystemTime };
 use std::thread;
 
-use serialport;
-use serialport::{ SerialPort, Parity, StopBits };
+use serialport::SerialPort;
 use byteorder::{ ByteOrder, LittleEndian };
 
-mod formal;
+pub mod formal;
 use crate::server::formal::*;
+use crate::monitor::{ trace_frame, FrameKind };
 mod process;
+pub mod device;
+pub mod store;
+pub mod image;
+pub mod identification;
+pub mod diagnostics;
+pub mod status;
+pub mod fifo;
+pub mod file;
+pub mod handler;
+pub mod state;
+use crate::server::device::{ Device, SharedDevice, Units };
+use crate::server::diagnostics::{ EVENT_RECEIVE_COMM_ERROR, EVENT_RECEIVE_OVERRUN };
+use crate::transport::{ Transport, MIN_FRAME_GAP };
+pub mod tcp;
+pub mod ascii;
 
-pub struct Server {
-	slave_id:          u8,
-	port:              Box<dyn SerialPort>,
-	discrete_input:    Vec<u8>,
-	coils:             Vec<u8>,
-	input_registers:   Vec<u16>,
-	holding_registers: Vec<u16>,
+pub struct Server<T: Transport = Box<dyn SerialPort>> {
+	port:              T,
+	// Обслуживаемые устройства, кадр направляется по первому байту
+	units:             Units,
 	query:             Vec<u8>,
 	pos:               usize,
 	query_len:         usize,
 	obuf:              Vec<u8>,
 	response_delay:    Duration,
+	// Пауза на линии, после которой начинается новый кадр (None - транспорт без символьного времени)
+	frame_gap:         Option<Duration>,
+	framing:           Framing,
+}
+
+// Режим кадрирования на последовательной линии
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+	Rtu,
+	Ascii,
 }
 
 pub const N_DISCRETE_INPUTS:   usize = 1024;
@@ -39,41 +60,61 @@ pub const N_INPUT_REGISTERS:   usize = 1024;
 pub const N_HOLDING_REGISTERS: usize = 1024;
 pub const IN_BUF_SIZE:         usize = 256;
 
-impl Server {
-	pub fn new(p: Box<dyn SerialPort>, slave_id: u8) -> Server {
-		let us_per_bit = 1000000f32 / p.baud_rate().unwrap() as f32;
-		let n_parity_bits = match p.parity().unwrap() {
-			Parity::None => 0,
-			Parity::Odd  => 1,
-			Parity::Even => 1,
-		};
-		let n_stop_bits = match p.stop_bits().unwrap() {
-			StopBits::One => 1,
-			StopBits::Two => 2,
-		};
-		let n_bits_per_symbol = 1 + 8 + n_parity_bits + n_stop_bits;
-		let us_per_symbol = us_per_bit * n_bits_per_symbol as f32;
-		dbg!(us_per_bit);
-		dbg!(n_bits_per_symbol);
-		dbg!(us_per_symbol);
-		
+impl<T: Transport> Server<T> {
+	pub fn new(p: T, slave_id: u8, device: SharedDevice, framing: Framing) -> Server<T> {
+		let mut units = Units::new();
+		units.insert(slave_id, device);
+		Server::with_units(p, units, framing)
+	}
+
+	pub fn with_units(p: T, units: Units, framing: Framing) -> Server<T> {
+		// Если транспорт не знает времени передачи символа, ответ отправляется без задержки
+		let response_delay = p.char_time().map(|t| t * 4).unwrap_or_default();
+		let frame_gap = p.char_time().map(|t| (t * 7 / 2).max(MIN_FRAME_GAP));
+
 		Server {
-			slave_id:          slave_id,
-			discrete_input:    vec![0; N_DISCRETE_INPUTS],
-			coils:             vec![0; N_COILS],
-			input_registers:   vec![0; N_INPUT_REGISTERS],
-			holding_registers: vec![0; N_HOLDING_REGISTERS],
+			units,
 			query:             vec![0; IN_BUF_SIZE],
 			obuf:              Vec::with_capacity(256),
 			query_len:         usize::MAX, // Недостаточно данных, чтобы определить длину пакета
-			response_delay:    Duration::from_micros((us_per_symbol * 4.0) as u64),
+			response_delay,
+			frame_gap,
 			pos:               0,
 			port:              p,
+			framing,
 		}
 	}
 
-	#[allow(unreachable_code)]
+	// Добавление устройства. Устройство с тем же адресом заменяется
+	pub fn add_unit(&mut self, slave_id: u8, device: SharedDevice) {
+		self.units.insert(slave_id, device);
+	}
+
+	pub fn units(&self) -> &Units {
+		&self.units
+	}
+
+	pub fn transport(&self) -> &T {
+		&self.port
+	}
+
+	pub fn transport_mut(&mut self) -> &mut T {
+		&mut self.port
+	}
+
 	pub fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+		match self.framing {
+			Framing::Rtu   => self.start_rtu(),
+			Framing::Ascii => self.start_ascii(),
+		}
+	}
+
+	#[allow(unreachable_code)]
+	fn start_rtu(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+		// Кадр, длину которого определить нельзя, пропускается до паузы на линии
+		let mut skip_frame = false;
+		// Время приёма последнего байта, по нему определяется пауза между кадрами
+		let mut last_rx: Option<Instant> = None;
 
 		loop {
 			if self.pos == 0 {
@@ -84,12 +125,32 @@ impl Server {
 			match self.port.read(&mut self.query[self.pos..pos_read_to]) {
 				Err(e) => {
 					println!("Ожидание, {}", e);
-					if self.pos != 0 { println!("RX {:02X?}", &self.query[..self.pos]); }
+					if self.pos != 0 { println!("{}", trace_frame(SystemTime::now(), FrameKind::Request, &self.query[..self.pos], None)); }
 					self.pos = 0;
+					skip_frame = false;
 					continue;
 				},
+				// Транспорт закрыт
+				Ok(0) => return Ok(()),
 				Ok(n) => {
 					println!("{} байт получено", n);
+					let now = Instant::now();
+					let pause = match (last_rx, self.frame_gap) {
+						(Some(t), Some(gap)) => now - t >= gap,
+						_ => false,
+					};
+					last_rx = Some(now);
+					// После паузы принятые байты начинают новый кадр, незаконченный кадр отбрасывается
+					if pause && self.pos != 0 {
+						println!("{}", trace_frame(SystemTime::now(), FrameKind::Request, &self.query[..self.pos], None));
+						self.query.copy_within(self.pos..self.pos + n, 0);
+						self.pos = 0;
+						self.query_len = usize::MAX;
+					}
+					if skip_frame {
+						if !pause { continue; }
+						skip_frame = false;
+					}
 					
 					self.pos += n;
 					if self.pos >= 2 {
@@ -98,19 +159,37 @@ impl Server {
 						dbg!(slave_id);
 						dbg!(function);
 
-						// Сравнение slave id
-						if self.slave_id != slave_id {
-							println!("Slave id не совпадает");
-							self.pos = 0;
-							continue;
-						}
+						// Поиск устройства по slave id. Широковещательные запросы принимаются всегда.
+						// Чужой кадр принимается целиком, чтобы не спутать его остаток со следующим кадром
+						let foreign = slave_id != BROADCAST_ID && !self.units.contains_key(&slave_id);
 
 						// Определение длины сообщения
 						if self.query_len == usize::MAX {
-							match self.get_query_len() {
+							let query_len = self.query_len(slave_id, &self.query[..self.pos]);
+							match query_len {
 								Ok(l) => self.query_len = l,
 								Err(e) => {
-									println!("RX {:02X?}", &self.query[..self.pos]);
+									println!("{}", trace_frame(SystemTime::now(), FrameKind::Request, &self.query[..self.pos], None));
+									if foreign {
+										println!("Slave id не совпадает");
+										self.for_bus(|d| d.diagnostics_mut().count_bus_message());
+										self.pos = 0;
+										skip_frame = true;
+										continue;
+									}
+									if e.overrun {
+										self.for_unit(slave_id, |d| {
+											d.diagnostics_mut().count_char_overrun();
+											d.diagnostics_mut().log_receive(EVENT_RECEIVE_OVERRUN);
+										});
+									}
+									// На широковещательный запрос ответ не отправляется даже при ошибке
+									if slave_id == BROADCAST_ID {
+										eprintln!("Ошибка: {}", e.message);
+										self.pos = 0;
+										skip_frame = true;
+										continue;
+									}
 									self.handle_exc(e, slave_id, function);
 									self.add_crc_and_flush()?;
 									self.pos = 0;
@@ -121,7 +200,17 @@ impl Server {
 						dbg!(self.query_len);
 						
 						if self.pos >= self.query_len {
-							println!("RX {:02X?}", &self.query[..self.query_len]);
+							println!("{}", trace_frame(SystemTime::now(), FrameKind::Request, &self.query[..self.query_len], None));
+							if foreign {
+								println!("Slave id не совпадает");
+								self.for_bus(|d| d.diagnostics_mut().count_bus_message());
+								// Неверная CRC - вероятно, это ответ другого устройства, длина которого
+								// не совпадает с длиной запроса. Остаток пропускается до паузы
+								let crc_rx = LittleEndian::read_u16(&self.query[self.query_len - 2..self.query_len]);
+								skip_frame = crc_rx != crc(&self.query[..self.query_len - 2]);
+								self.pos = 0;
+								continue;
+							}
 
 							// Check CRC
 							let crc_rx: u16 = LittleEndian::read_u16(&self.query[self.query_len - 2..self.query_len]);
@@ -130,17 +219,28 @@ impl Server {
 							dbg!(crc_calc);
 							if crc_rx != crc_calc {
 								eprintln!("Ошибка CRC. Запрос проигнорирован.");
+								self.for_bus(|d| {
+									d.diagnostics_mut().count_comm_error();
+									d.diagnostics_mut().log_receive(EVENT_RECEIVE_COMM_ERROR);
+								});
 								self.pos = 0;
 								continue;
 							}
 							
-							match self.process_function_code() {
-								Ok(data) => {
+							let result = self.process_bus_query(slave_id, &self.query[..self.query_len - 2]);
+							match result {
+								None => {
+									println!("Ответ не отправляется");
+									self.pos = 0;
+									continue;
+								},
+								Some(Ok(data)) => {
+									self.for_unit(slave_id, |d| d.diagnostics_mut().log_send(None));
 									self.obuf.push(slave_id);
 									self.obuf.push(function);
 									self.obuf.extend_from_slice(data.as_slice());
 								},
-								Err(e) => self.handle_exc(e, slave_id, function),
+								Some(Err(e)) => self.handle_exc(e, slave_id, function),
 							}
 						}
 						else { continue; }
@@ -155,46 +255,6 @@ impl Server {
 		}
 		Ok(())
 	}
-	
-	// Вычисление длины запроса, если её не получается определить по коду функции
-	// Здесь к длине прибавляется 3 (+1+2)
-	// +1 - длина device id
-	// +2 - длина CRC
-	// Возвращает Ok(usize::MAX), если длину пока определить нельзя
-	fn get_query_len(&self) -> Result<usize, MbExcWithMessage> {
-		if self.pos < 2 { return Ok(usize::MAX); }
-		let function: u8 = self.query[1];
-		if (function as usize) < QUERY_LEN.len() {
-			match QUERY_LEN[function as usize] {
-				usize::MAX => {
-					let function_enum = num::FromPrimitive::from_u8(function);
-					let answer = match function_enum {
-						Some(MbFunc::WriteMultipleRegisters) => {
-							if self.pos > 6 { Ok((self.query[6] + 6 + 1 + 2) as usize) }
-							else { Ok(usize::MAX) }
-						},
-						Some(MbFunc::WriteMultipleCoils) => {
-							if self.pos > 6 { Ok((self.query[6] + 6 + 1 + 2) as usize) }
-							else { Ok(usize::MAX) }
-						}
-						Some(_) => Err(MbExcWithMessage::new(MbExc::SlaveDeviceFailure, "Попытка вычислить длину сообщения со статической длиной".into())),
-						
-						None => Err(MbExcWithMessage::new(MbExc::IllegalFunction, STR_ILLEGAL_FUNCTION.into())),
-					};
-					match answer {
-						Ok(usize::MAX) => answer,
-						Ok(l) => {
-							if l > IN_BUF_SIZE { Err(MbExcWithMessage::new(MbExc::SlaveDeviceFailure, "Вычислена неверная длина пакета".into())) }
-							else { answer }
-						},
-						_ => answer,
-					}
-				},
-				0 => Err(MbExcWithMessage::new(MbExc::IllegalFunction, STR_ILLEGAL_FUNCTION.into())),
-				fixed => Ok(fixed + 1 + 2),
-			}
-		} else { Err(MbExcWithMessage::new(MbExc::IllegalFunction, STR_ILLEGAL_FUNCTION.into())) }
-	}
 
 	// Финальная обработка отправляемого пакета.
 	// В конец добавляется контрольная сумма,
@@ -202,10 +262,12 @@ impl Server {
 	fn add_crc_and_flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
 		let crc_tx = crc(self.obuf.as_slice());
 		self.obuf.extend_from_slice(&crc_tx.to_le_bytes());
-		println!("TX {:02X?}", self.obuf);
+		// Запрос без CRC, если он принят целиком, иначе принятое начало запроса
+		let request = if self.pos >= self.query_len { &self.query[..self.query_len - 2] } else { &self.query[..self.pos] };
+		println!("{}", trace_frame(SystemTime::now(), FrameKind::Response, &self.obuf, Some(request)));
 		thread::sleep(self.response_delay);
 		// Запись в последовательный порт
-		self.port.write(self.obuf.as_slice())?;
+		self.port.write_frame(self.obuf.as_slice())?;
 		self.obuf.clear();
 		Ok(())
 	}
@@ -213,10 +275,331 @@ impl Server {
 	// Формирование ответа в случае возникновения исключения.
 	// В соответствии со спецификацией исключений Modbus
 	fn handle_exc(&mut self, e: MbExcWithMessage, slave_id: u8, function: u8) {
-		let MbExcWithMessage { exc, message } = e;
+		let MbExcWithMessage { exc, message, .. } = e;
 		eprintln!("Ошибка: {}", message);
+		self.for_unit(slave_id, |d| {
+			d.diagnostics_mut().count_exception();
+			d.diagnostics_mut().log_send(Some(exc));
+		});
 		self.obuf.push(slave_id);
-		self.obuf.push(function + 0x80);
+		self.obuf.push(function | 0x80);
 		self.obuf.push(exc as u8);
 	}
+
+	// Событие на линии, которое учитывают все устройства
+	fn for_bus<F: FnMut(&mut Device)>(&self, mut f: F) {
+		for device in self.units.values() {
+			f(&mut device.lock().unwrap());
+		}
+	}
+
+	// Событие, относящееся к запросу: для широковещательного запроса - все устройства
+	fn for_unit<F: FnMut(&mut Device)>(&self, slave_id: u8, mut f: F) {
+		if slave_id == BROADCAST_ID { return self.for_bus(f); }
+		if let Some(device) = self.units.get(&slave_id) {
+			f(&mut device.lock().unwrap());
+		}
+	}
+
+	// Длина кадра RTU. Для широковещательного запроса и чужого кадра
+	// правила длины берутся у первого устройства
+	fn query_len(&self, slave_id: u8, query: &[u8]) -> Result<usize, MbExcWithMessage> {
+		match self.units.get(&slave_id).or_else(|| self.units.values().next()) {
+			Some(device) => device.lock().unwrap().query_len(query),
+			None         => get_query_len(query),
+		}
+	}
+
+	// Обработка принятого запроса. Широковещательный запрос выполняют все устройства
+	fn process_bus_query(&self, slave_id: u8, query: &[u8]) -> Option<Result<Vec<u8>, MbExcWithMessage>> {
+		if slave_id == BROADCAST_ID {
+			for device in self.units.values() {
+				device.lock().unwrap().process_bus_query(query);
+			}
+			return None;
+		}
+		// Остальные устройства видят кадр на линии
+		for (&id, device) in self.units.iter() {
+			if id != slave_id { device.lock().unwrap().diagnostics_mut().count_bus_message(); }
+		}
+		self.units.get(&slave_id)?.lock().unwrap().process_bus_query(query)
+	}
+}
+
+// Вычисление длины запроса, если её не получается определить по коду функции
+// Здесь к длине прибавляется 3 (+1+2)
+// +1 - длина device id
+// +2 - длина CRC
+// Возвращает Ok(usize::MAX), если длину пока определить нельзя
+pub fn get_query_len(query: &[u8]) -> Result<usize, MbExcWithMessage> {
+	if query.len() < 2 { return Ok(usize::MAX); }
+	let function: u8 = query[1];
+	if (function as usize) < QUERY_LEN.len() {
+		match QUERY_LEN[function as usize] {
+			usize::MAX => {
+				let function_enum = num::FromPrimitive::from_u8(function);
+				let answer = match function_enum {
+					Some(MbFunc::WriteMultipleRegisters) => {
+						if query.len() > 6 { Ok(query[6] as usize + 6 + 1 + 2) }
+						else { Ok(usize::MAX) }
+					},
+					Some(MbFunc::WriteMultipleCoils) => {
+						if query.len() > 6 { Ok(query[6] as usize + 6 + 1 + 2) }
+						else { Ok(usize::MAX) }
+					}
+					Some(MbFunc::ReadFileRecord) | Some(MbFunc::WriteFileRecord) => {
+						if query.len() > 2 { Ok(query[2] as usize + 2 + 1 + 2) }
+						else { Ok(usize::MAX) }
+					},
+					Some(MbFunc::ReadWriteMultipleRegisters) => {
+						if query.len() > 10 { Ok(query[10] as usize + 10 + 1 + 2) }
+						else { Ok(usize::MAX) }
+					},
+					Some(_) => Err(MbExcWithMessage::new(MbExc::SlaveDeviceFailure, "Попытка вычислить длину сообщения со статической длиной".into())),
+					
+					None => Err(MbExcWithMessage::new(MbExc::IllegalFunction, STR_ILLEGAL_FUNCTION.into())),
+				};
+				match answer {
+					Ok(usize::MAX) => answer,
+					Ok(l) => {
+						if l > IN_BUF_SIZE { Err(MbExcWithMessage::overrun()) }
+						else { answer }
+					},
+					_ => answer,
+				}
+			},
+			0 => Err(MbExcWithMessage::new(MbExc::IllegalFunction, STR_ILLEGAL_FUNCTION.into())),
+			fixed => Ok(fixed + 1 + 2),
+		}
+	} else { Err(MbExcWithMessage::new(MbExc::IllegalFunction, STR_ILLEGAL_FUNCTION.into())) }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::server::device::Device;
+	use crate::transport::MemoryTransport;
+
+	fn rtu(adu: &[u8]) -> Vec<u8> {
+		let mut frame = adu.to_vec();
+		frame.extend_from_slice(&crc(adu).to_le_bytes());
+		frame
+	}
+
+	// Последовательная линия 9600 8E1: кадры приходят с паузой 20 мс,
+	// без таймаутов чтения между ними
+	struct TimedTransport {
+		input:   std::collections::VecDeque<Vec<u8>>,
+		output:  Vec<Vec<u8>>,
+		// Пауза перед текущим кадром уже выдержана
+		started: bool,
+	}
+
+	impl Transport for TimedTransport {
+		fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+			let frame = match self.input.front_mut() {
+				Some(f) => f,
+				None    => return Ok(0),
+			};
+			if !self.started {
+				thread::sleep(Duration::from_millis(20));
+				self.started = true;
+			}
+			let n = buf.len().min(frame.len());
+			buf[..n].copy_from_slice(&frame[..n]);
+			frame.drain(..n);
+			if frame.is_empty() {
+				self.input.pop_front();
+				self.started = false;
+			}
+			Ok(n)
+		}
+
+		fn write_frame(&mut self, frame: &[u8]) -> std::io::Result<()> {
+			self.output.push(frame.to_vec());
+			Ok(())
+		}
+
+		fn set_timeout(&mut self, _timeout: Duration) -> std::io::Result<()> {
+			Ok(())
+		}
+
+		fn char_time(&self) -> Option<Duration> {
+			Some(Duration::from_micros(1146))
+		}
+	}
+
+	fn run(framing: Framing, frames: &[Vec<u8>]) -> Vec<Vec<u8>> {
+		let mut transport = MemoryTransport::new();
+		for f in frames.iter() { transport.push_frame(f); }
+		let mut server = Server::new(transport, 1, Device::new().shared(), framing);
+		server.start().unwrap();
+		server.transport_mut().take_written()
+	}
+
+	#[test]
+	fn rtu_request_response() {
+		let written = run(Framing::Rtu, &[
+			rtu(&[1, 0x06, 0x00, 0x02, 0x12, 0x34]),
+			rtu(&[1, 0x03, 0x00, 0x02, 0x00, 0x01]),
+		]);
+		assert_eq!(written, vec![
+			rtu(&[1, 0x06, 0x00, 0x02, 0x12, 0x34]),
+			rtu(&[1, 0x03, 0x02, 0x12, 0x34]),
+		]);
+	}
+
+	#[test]
+	fn rtu_ignores_foreign_and_corrupted_frames() {
+		let mut corrupted = rtu(&[1, 0x03, 0x00, 0x00, 0x00, 0x01]);
+		corrupted[7] ^= 0xFF;
+		let written = run(Framing::Rtu, &[
+			corrupted,
+			rtu(&[2, 0x03, 0x00, 0x00, 0x00, 0x01]),
+			rtu(&[1, 0x03, 0x00, 0x00, 0x00, 0x00]),
+		]);
+		assert_eq!(written, vec![rtu(&[1, 0x83, MbExc::IllegalDataValue as u8])]);
+	}
+
+	#[test]
+	fn rtu_foreign_frames_without_gap() {
+		// Чужие запросы и запросы к устройству идут подряд без паузы
+		let mut chunk = rtu(&[2, 0x03, 0x00, 0x00, 0x00, 0x01]);
+		chunk.extend(rtu(&[1, 0x06, 0x00, 0x00, 0x00, 0x05]));
+		chunk.extend(rtu(&[2, 0x10, 0x00, 0x00, 0x00, 0x01, 0x02, 0xAB, 0xCD]));
+		chunk.extend(rtu(&[1, 0x03, 0x00, 0x00, 0x00, 0x01]));
+		let written = run(Framing::Rtu, &[
+			chunk,
+			// Ответ другого устройства длиннее запроса, остаток пропускается до паузы
+			rtu(&[2, 0x03, 0x04, 0x00, 0x01, 0x00, 0x02]),
+			rtu(&[1, 0x03, 0x00, 0x00, 0x00, 0x01]),
+		]);
+		assert_eq!(written, vec![
+			rtu(&[1, 0x06, 0x00, 0x00, 0x00, 0x05]),
+			rtu(&[1, 0x03, 0x02, 0x00, 0x05]),
+			rtu(&[1, 0x03, 0x02, 0x00, 0x05]),
+		]);
+	}
+
+	#[test]
+	fn rtu_foreign_response_shorter_than_request() {
+		// Запрос к устройству 2, его ответ короче запроса, затем запрос к устройству 1
+		let input = [
+			rtu(&[2, 0x03, 0x00, 0x00, 0x00, 0x01]),
+			rtu(&[2, 0x03, 0x02, 0x00, 0x07]),
+			rtu(&[1, 0x03, 0x00, 0x00, 0x00, 0x01]),
+		];
+		let transport = TimedTransport { input: input.iter().cloned().collect(), output: Vec::new(), started: false };
+		let mut server = Server::new(transport, 1, Device::new().shared(), Framing::Rtu);
+		server.start().unwrap();
+		assert_eq!(server.transport().output, vec![rtu(&[1, 0x03, 0x02, 0x00, 0x00])]);
+	}
+
+	#[test]
+	fn rtu_illegal_function() {
+		let written = run(Framing::Rtu, &[rtu(&[1, 0x42])]);
+		assert_eq!(written, vec![rtu(&[1, 0xC2, MbExc::IllegalFunction as u8])]);
+	}
+
+	#[test]
+	fn rtu_diagnostic_counters() {
+		let mut corrupted = rtu(&[1, 0x03, 0x00, 0x00, 0x00, 0x01]);
+		corrupted[7] ^= 0xFF;
+		let written = run(Framing::Rtu, &[
+			corrupted,
+			rtu(&[2, 0x03, 0x00, 0x00, 0x00, 0x01]),
+			rtu(&[1, 0x03, 0x00, 0x00, 0x00, 0x00]),
+			rtu(&[1, 0x08, 0x00, 0x0B, 0x00, 0x00]),
+			rtu(&[1, 0x08, 0x00, 0x0C, 0x00, 0x00]),
+			rtu(&[1, 0x08, 0x00, 0x0D, 0x00, 0x00]),
+			rtu(&[1, 0x08, 0x00, 0x04, 0x00, 0x00]),
+			rtu(&[1, 0x03, 0x00, 0x00, 0x00, 0x01]),
+			rtu(&[1, 0x08, 0x00, 0x01, 0x00, 0x00]),
+			rtu(&[1, 0x08, 0x00, 0x0E, 0x00, 0x00]),
+		]);
+		assert_eq!(written, vec![
+			rtu(&[1, 0x83, MbExc::IllegalDataValue as u8]),
+			rtu(&[1, 0x08, 0x00, 0x0B, 0x00, 0x03]),
+			rtu(&[1, 0x08, 0x00, 0x0C, 0x00, 0x01]),
+			rtu(&[1, 0x08, 0x00, 0x0D, 0x00, 0x01]),
+			// Ответы в режиме только прослушивания не отправляются,
+			// перезапуск связи очищает счётчики
+			rtu(&[1, 0x08, 0x00, 0x0E, 0x00, 0x01]),
+		]);
+	}
+
+	#[test]
+	fn rtu_broadcast() {
+		let written = run(Framing::Rtu, &[
+			rtu(&[0, 0x10, 0x00, 0x00, 0x00, 0x01, 0x02, 0xAB, 0xCD]),
+			rtu(&[0, 0x03, 0x00, 0x00, 0x00, 0x01]),
+			rtu(&[0, 0x42]),
+			rtu(&[1, 0x03, 0x00, 0x00, 0x00, 0x01]),
+		]);
+		assert_eq!(written, vec![rtu(&[1, 0x03, 0x02, 0xAB, 0xCD])]);
+	}
+
+	#[test]
+	fn rtu_multiple_units() {
+		let mut transport = MemoryTransport::new();
+		for f in [
+			rtu(&[2, 0x06, 0x00, 0x00, 0x00, 0x02]),
+			rtu(&[0, 0x06, 0x00, 0x01, 0x00, 0x09]),
+			rtu(&[1, 0x03, 0x00, 0x00, 0x00, 0x02]),
+			rtu(&[2, 0x03, 0x00, 0x00, 0x00, 0x02]),
+			rtu(&[3, 0x03, 0x00, 0x00, 0x00, 0x02]),
+		].iter() { transport.push_frame(f); }
+		let mut server = Server::new(transport, 1, Device::new().shared(), Framing::Rtu);
+		server.add_unit(2, Device::new().shared());
+		server.start().unwrap();
+		assert_eq!(server.transport_mut().take_written(), vec![
+			rtu(&[2, 0x06, 0x00, 0x00, 0x00, 0x02]),
+			rtu(&[1, 0x03, 0x04, 0x00, 0x00, 0x00, 0x09]),
+			rtu(&[2, 0x03, 0x04, 0x00, 0x02, 0x00, 0x09]),
+		]);
+		// Кадр для отсутствующего устройства учитывается всеми устройствами линии
+		assert_eq!(server.units()[&1].lock().unwrap().diagnostics().counters.bus_message, 5);
+	}
+
+	#[test]
+	fn ascii_request_response() {
+		let written = run(Framing::Ascii, &[
+			b":010600020102F4\r\n".to_vec(),
+			b":010300020001F9\r\n".to_vec(),
+			b":010300020001F0\r\n".to_vec(),
+		]);
+		assert_eq!(written, vec![
+			b":010600020102F4\r\n".to_vec(),
+			b":0103020102F7\r\n".to_vec(),
+		]);
+	}
+
+	#[test]
+	fn query_len_fixed() {
+		assert_eq!(get_query_len(&[1]).unwrap(), usize::MAX);
+		assert_eq!(get_query_len(&[1, 0x03]).unwrap(), 8);
+		assert_eq!(get_query_len(&[1, 0x06]).unwrap(), 8);
+	}
+
+	#[test]
+	fn query_len_dynamic() {
+		assert_eq!(get_query_len(&[1, 0x10, 0, 0, 0, 2]).unwrap(), usize::MAX);
+		assert_eq!(get_query_len(&[1, 0x10, 0, 0, 0, 2, 4]).unwrap(), 13);
+		assert_eq!(get_query_len(&[1, 0x0F, 0, 0, 0, 240, 30]).unwrap(), 9 + 30);
+		let e = get_query_len(&[1, 0x0F, 0, 0, 0, 0, 0xFF]).err().unwrap();
+		assert_eq!(e.exc, MbExc::SlaveDeviceFailure);
+		assert!(e.overrun);
+		assert_eq!(get_query_len(&[1, 0x17, 0, 0, 0, 1, 0, 0, 0, 1]).unwrap(), usize::MAX);
+		assert_eq!(get_query_len(&[1, 0x17, 0, 0, 0, 1, 0, 0, 0, 1, 2]).unwrap(), 15);
+		assert_eq!(get_query_len(&[1, 0x14]).unwrap(), usize::MAX);
+		assert_eq!(get_query_len(&[1, 0x14, 7]).unwrap(), 12);
+		assert_eq!(get_query_len(&[1, 0x15, 0xFB]).unwrap(), IN_BUF_SIZE);
+	}
+
+	#[test]
+	fn query_len_illegal_function() {
+		assert_eq!(get_query_len(&[1, 0x00]).err().unwrap().exc, MbExc::IllegalFunction);
+		assert_eq!(get_query_len(&[1, 0x85]).err().unwrap().exc, MbExc::IllegalFunction);
+		assert!(!get_query_len(&[1, 0x85]).err().unwrap().overrun);
+	}
 }
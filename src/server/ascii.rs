@@ -0,0 +1,143 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Кадрирование Modbus ASCII (':' + HEX + LRC + CRLF)
+//------------------------------------------------------------------------------
+use std::thread;
+use std::time::SystemTime;
+
+use crate::server::{ Server, IN_BUF_SIZE };
+use crate::server::formal::*;
+use crate::server::diagnostics::{ EVENT_RECEIVE_COMM_ERROR, EVENT_RECEIVE_OVERRUN };
+use crate::transport::Transport;
+use crate::monitor::{ trace_adu, FrameKind };
+
+// Максимальная длина кадра ASCII: ':' + два символа на байт + CR LF
+pub const ASCII_BUF_SIZE: usize = 1 + IN_BUF_SIZE * 2 + 2;
+
+impl<T: Transport> Server<T> {
+	#[allow(unreachable_code)]
+	pub(super) fn start_ascii(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+		let mut frame: Vec<u8> = Vec::with_capacity(ASCII_BUF_SIZE);
+		let mut byte = [0u8; 1];
+
+		loop {
+			match self.port.read(&mut byte) {
+				Err(e) => {
+					// Пауза между символами больше таймаута - кадр недействителен
+					println!("Ожидание, {}", e);
+					if !frame.is_empty() { println!("RX {:?}", String::from_utf8_lossy(&frame)); }
+					frame.clear();
+					continue;
+				},
+				// Транспорт закрыт
+				Ok(0) => return Ok(()),
+				Ok(_) => {
+					let c = byte[0];
+					// ':' всегда начинает новый кадр, даже если предыдущий не завершён
+					if c == b':' {
+						frame.clear();
+						frame.push(c);
+						continue;
+					}
+					// Символы вне кадра игнорируются
+					if frame.is_empty() { continue; }
+					frame.push(c);
+					if frame.len() > ASCII_BUF_SIZE {
+						eprintln!("Кадр ASCII слишком длинный. Запрос проигнорирован.");
+						self.for_bus(|d| {
+							d.diagnostics_mut().count_char_overrun();
+							d.diagnostics_mut().log_receive(EVENT_RECEIVE_OVERRUN);
+						});
+						frame.clear();
+						continue;
+					}
+					if c != b'\n' || frame[frame.len() - 2] != b'\r' { continue; }
+				},
+			}
+
+			let query = match ascii_decode(&frame[1..frame.len() - 2]) {
+				Some(q) if q.len() >= 3 => q,
+				_ => {
+					eprintln!("Кадр ASCII {:?} содержит недопустимые символы. Запрос проигнорирован.", String::from_utf8_lossy(&frame));
+					self.for_bus(|d| {
+						d.diagnostics_mut().count_comm_error();
+						d.diagnostics_mut().log_receive(EVENT_RECEIVE_COMM_ERROR);
+					});
+					frame.clear();
+					continue;
+				},
+			};
+			frame.clear();
+
+			// Check LRC
+			let lrc_rx = query[query.len() - 1];
+			let query = &query[..query.len() - 1];
+			let lrc_calc = lrc(query);
+			if lrc_rx != lrc_calc {
+				eprintln!("Ошибка LRC. Запрос {:02X?} проигнорирован.", query);
+				self.for_bus(|d| {
+					d.diagnostics_mut().count_comm_error();
+					d.diagnostics_mut().log_receive(EVENT_RECEIVE_COMM_ERROR);
+				});
+				continue;
+			}
+
+			println!("{}", trace_adu(SystemTime::now(), FrameKind::Request, query, None));
+			let slave_id = query[0];
+			let function = query[1];
+			if slave_id != BROADCAST_ID && !self.units.contains_key(&slave_id) {
+				println!("Slave id не совпадает");
+				self.for_bus(|d| d.diagnostics_mut().count_bus_message());
+				continue;
+			}
+
+			// В ASCII длина кадра известна заранее, она должна совпадать с ожидаемой для функции.
+			// query_len возвращает длину кадра RTU, включая CRC
+			let result = match self.query_len(slave_id, query) {
+				Ok(l) if l == query.len() + 2 => self.process_bus_query(slave_id, query),
+				// На широковещательный запрос ответ не отправляется даже при ошибке
+				_ if slave_id == BROADCAST_ID => None,
+				Ok(_) => Some(Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_LENGTH.into()))),
+				Err(e) => Some(Err(e)),
+			};
+			match result {
+				None => {
+					println!("Ответ не отправляется");
+					continue;
+				},
+				Some(Ok(data)) => {
+					self.for_unit(slave_id, |d| d.diagnostics_mut().log_send(None));
+					self.obuf.push(slave_id);
+					self.obuf.push(function);
+					self.obuf.extend_from_slice(data.as_slice());
+				},
+				Some(Err(e)) => self.handle_exc(e, slave_id, function),
+			}
+			self.add_lrc_and_flush(query)?;
+		}
+		Ok(())
+	}
+
+	// Финальная обработка отправляемого пакета ASCII.
+	// В конец добавляется LRC, пакет кодируется в HEX
+	// и записывается в порт между ':' и CR LF.
+	// request - запрос, на который отправляется ответ
+	fn add_lrc_and_flush(&mut self, request: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+		println!("{}", trace_adu(SystemTime::now(), FrameKind::Response, &self.obuf, Some(request)));
+		let lrc_tx = lrc(self.obuf.as_slice());
+		self.obuf.push(lrc_tx);
+		let mut frame = Vec::with_capacity(1 + self.obuf.len() * 2 + 2);
+		frame.push(b':');
+		ascii_encode(self.obuf.as_slice(), &mut frame);
+		frame.extend_from_slice(b"\r\n");
+		thread::sleep(self.response_delay);
+		self.port.write_frame(frame.as_slice())?;
+		self.obuf.clear();
+		Ok(())
+	}
+}
@@ -0,0 +1,90 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Образ устройства: таблицы Modbus, общие для всех транспортов
+//------------------------------------------------------------------------------
+use std::collections::{ BTreeMap, VecDeque };
+use std::sync::{ Arc, Mutex };
+
+use crate::server::store::{ DataStore, VecStore };
+use crate::server::identification::DeviceIdentification;
+use crate::server::diagnostics::Diagnostics;
+use crate::server::status::{ ServerId, N_EXCEPTION_STATUS_BITS };
+use crate::server::handler::UserFunction;
+
+pub struct Device {
+	pub(super) store: Box<dyn DataStore>,
+	// Счётчик записей в coils и holding registers
+	generation:       u64,
+	pub(super) identification: DeviceIdentification,
+	pub(super) diagnostics:    Diagnostics,
+	pub(super) server_id:      ServerId,
+	pub(super) exception_coils: [Option<u16>; N_EXCEPTION_STATUS_BITS],
+	// Очереди FIFO по адресу указателя
+	pub(super) fifos:          BTreeMap<u16, VecDeque<u16>>,
+	// Файлы записей по номеру файла
+	pub(super) files:          BTreeMap<u16, Vec<u16>>,
+	// Обработчики пользовательских функций по коду функции
+	pub(super) functions:      BTreeMap<u8, UserFunction>,
+}
+
+// Устройство, разделяемое между последовательным и TCP серверами
+pub type SharedDevice = Arc<Mutex<Device>>;
+
+// Устройства одной линии по адресу (slave id / unit id)
+pub type Units = BTreeMap<u8, SharedDevice>;
+
+impl Device {
+	pub fn new() -> Device {
+		Device::with_store(Box::new(VecStore::default()))
+	}
+
+	pub fn with_store(store: Box<dyn DataStore>) -> Device {
+		Device {
+			store,
+			generation: 0,
+			identification: DeviceIdentification::default(),
+			diagnostics: Diagnostics::default(),
+			server_id: ServerId::default(),
+			exception_coils: [None; N_EXCEPTION_STATUS_BITS],
+			fifos: BTreeMap::new(),
+			files: BTreeMap::new(),
+			functions: BTreeMap::new(),
+		}
+	}
+
+	pub fn store_mut(&mut self) -> &mut dyn DataStore {
+		self.store.as_mut()
+	}
+
+	// Номер версии записываемых таблиц. Меняется при каждой записи через Modbus
+	pub fn generation(&self) -> u64 {
+		self.generation
+	}
+
+	pub(super) fn mark_written(&mut self) {
+		self.generation = self.generation.wrapping_add(1);
+	}
+
+	pub fn identification(&self) -> &DeviceIdentification {
+		&self.identification
+	}
+
+	pub fn set_identification(&mut self, identification: DeviceIdentification) {
+		self.identification = identification;
+	}
+
+	pub fn shared(self) -> SharedDevice {
+		Arc::new(Mutex::new(self))
+	}
+}
+
+impl Default for Device {
+	fn default() -> Device {
+		Device::new()
+	}
+}
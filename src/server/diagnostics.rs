@@ -0,0 +1,343 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Диагностика последовательной линии (функция 0x08): счётчики
+// и режим только прослушивания (Listen Only).
+// Счётчик и журнал коммуникационных событий (функции 0x0B, 0x0C)
+//------------------------------------------------------------------------------
+use std::collections::VecDeque;
+
+use crate::server::device::Device;
+use crate::server::formal::*;
+
+// Подфункции 0x08
+pub const DIAG_RETURN_QUERY_DATA:           u16 = 0x00;
+pub const DIAG_RESTART_COMMUNICATIONS:      u16 = 0x01;
+pub const DIAG_RETURN_DIAGNOSTIC_REGISTER:  u16 = 0x02;
+pub const DIAG_FORCE_LISTEN_ONLY:           u16 = 0x04;
+pub const DIAG_CLEAR_COUNTERS:              u16 = 0x0A;
+pub const DIAG_BUS_MESSAGE_COUNT:           u16 = 0x0B;
+pub const DIAG_BUS_COMM_ERROR_COUNT:        u16 = 0x0C;
+pub const DIAG_BUS_EXCEPTION_COUNT:         u16 = 0x0D;
+pub const DIAG_SERVER_MESSAGE_COUNT:        u16 = 0x0E;
+pub const DIAG_SERVER_NO_RESPONSE_COUNT:    u16 = 0x0F;
+pub const DIAG_BUS_CHAR_OVERRUN_COUNT:      u16 = 0x12;
+pub const DIAG_CLEAR_OVERRUN:               u16 = 0x14;
+
+// Коммуникационные события. Событие приёма
+pub const EVENT_RECEIVE:             u8 = 0x80;
+pub const EVENT_RECEIVE_COMM_ERROR:  u8 = 0x02;
+pub const EVENT_RECEIVE_OVERRUN:     u8 = 0x10;
+pub const EVENT_RECEIVE_LISTEN_ONLY: u8 = 0x20;
+pub const EVENT_RECEIVE_BROADCAST:   u8 = 0x40;
+// Событие отправки
+pub const EVENT_SEND:                u8 = 0x40;
+pub const EVENT_SEND_READ_EXC:       u8 = 0x01;
+pub const EVENT_SEND_ABORT_EXC:      u8 = 0x02;
+pub const EVENT_SEND_BUSY_EXC:       u8 = 0x04;
+pub const EVENT_SEND_NAK_EXC:        u8 = 0x08;
+pub const EVENT_SEND_LISTEN_ONLY:    u8 = 0x20;
+// Вход в режим только прослушивания и перезапуск связи
+pub const EVENT_LISTEN_ONLY:         u8 = 0x04;
+pub const EVENT_RESTART:             u8 = 0x00;
+
+// Слово состояния: устройство не занято обработкой предыдущей команды
+pub const COMM_STATUS_READY: u16 = 0x0000;
+
+// Размер журнала событий
+pub const EVENT_LOG_SIZE: usize = 64;
+
+// Счётчики с момента запуска, последней очистки или перезапуска связи.
+// По спецификации счётчики 16-битные и переполняются через ноль
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counters {
+	// Все кадры, замеченные на линии
+	pub bus_message:        u16,
+	// Кадры с ошибкой CRC / LRC
+	pub bus_comm_error:     u16,
+	// Отправленные ответы-исключения
+	pub bus_exception:      u16,
+	// Кадры, адресованные устройству
+	pub server_message:     u16,
+	// Адресованные устройству кадры, оставшиеся без ответа
+	pub server_no_response: u16,
+	// Кадры, не поместившиеся в приёмный буфер
+	pub bus_char_overrun:   u16,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Diagnostics {
+	pub counters:    Counters,
+	// Регистр диагностики, содержимое определяется устройством
+	pub register:    u16,
+	listen_only:     bool,
+	// Успешно обработанные запросы
+	event_count:     u16,
+	// Журнал событий, последнее событие в начале
+	events:          VecDeque<u8>,
+}
+
+impl Diagnostics {
+	pub fn listen_only(&self) -> bool {
+		self.listen_only
+	}
+
+	pub fn count_bus_message(&mut self)    { inc(&mut self.counters.bus_message); }
+	pub fn count_comm_error(&mut self)     { inc(&mut self.counters.bus_comm_error); }
+	pub fn count_exception(&mut self)      { inc(&mut self.counters.bus_exception); }
+	pub fn count_no_response(&mut self)    { inc(&mut self.counters.server_no_response); }
+	pub fn count_char_overrun(&mut self)   { inc(&mut self.counters.bus_char_overrun); }
+
+	pub fn count_server_message(&mut self) {
+		inc(&mut self.counters.bus_message);
+		inc(&mut self.counters.server_message);
+	}
+
+	pub fn event_count(&self) -> u16 {
+		self.event_count
+	}
+
+	pub fn events(&self) -> &VecDeque<u8> {
+		&self.events
+	}
+
+	pub fn log_event(&mut self, event: u8) {
+		if self.events.len() == EVENT_LOG_SIZE { self.events.pop_back(); }
+		self.events.push_front(event);
+	}
+
+	// Событие приёма, flags - EVENT_RECEIVE_*
+	pub fn log_receive(&mut self, flags: u8) {
+		let listen_only = if self.listen_only { EVENT_RECEIVE_LISTEN_ONLY } else { 0 };
+		self.log_event(EVENT_RECEIVE | listen_only | flags);
+	}
+
+	// Событие отправки ответа, exc - отправленное исключение
+	pub fn log_send(&mut self, exc: Option<MbExc>) {
+		let flags = match exc {
+			None => 0,
+			Some(MbExc::IllegalFunction) | Some(MbExc::IllegalDataAddress) | Some(MbExc::IllegalDataValue) => EVENT_SEND_READ_EXC,
+			Some(MbExc::SlaveDeviceFailure) => EVENT_SEND_ABORT_EXC,
+			Some(MbExc::Acknowledge) | Some(MbExc::SlaveDeviceBusy) => EVENT_SEND_BUSY_EXC,
+			Some(_) => EVENT_SEND_NAK_EXC,
+		};
+		let listen_only = if self.listen_only { EVENT_SEND_LISTEN_ONLY } else { 0 };
+		self.log_event(EVENT_SEND | listen_only | flags);
+	}
+
+	// Очистка счётчиков, регистра диагностики и счётчика событий.
+	// Журнал событий сохраняется
+	pub fn clear(&mut self) {
+		self.counters = Counters::default();
+		self.register = 0;
+		self.event_count = 0;
+	}
+}
+
+fn inc(counter: &mut u16) {
+	*counter = counter.wrapping_add(1);
+}
+
+// Функции, которые можно выполнить широковещательно
+fn is_broadcast_function(function: u8) -> bool {
+	matches!(
+		num::FromPrimitive::from_u8(function),
+		Some(MbFunc::WriteSingleCoil) | Some(MbFunc::WriteSingleRegister)
+			| Some(MbFunc::WriteMultipleCoils) | Some(MbFunc::WriteMultipleRegisters)
+			| Some(MbFunc::WriteFileRecord) | Some(MbFunc::MaskWriteRegister)
+	)
+}
+
+// Запрос Restart Communications Option - единственный,
+// который обрабатывается в режиме только прослушивания
+fn is_restart(query: &[u8]) -> bool {
+	query.len() >= 4 && query[1] == MbFunc::Diagnostics as u8
+		&& u16::from_be_bytes([query[2], query[3]]) == DIAG_RESTART_COMMUNICATIONS
+}
+
+impl Device {
+	pub fn diagnostics(&self) -> &Diagnostics {
+		&self.diagnostics
+	}
+
+	pub fn diagnostics_mut(&mut self) -> &mut Diagnostics {
+		&mut self.diagnostics
+	}
+
+	// Обработка запроса, принятого с последовательной линии и адресованного устройству
+	// или всем устройствам (широковещательный). Учитывает счётчики и режим
+	// только прослушивания. None - ответ не отправляется
+	pub fn process_bus_query(&mut self, query: &[u8]) -> Option<Result<Vec<u8>, MbExcWithMessage>> {
+		self.diagnostics.count_server_message();
+		if query[0] == BROADCAST_ID {
+			self.diagnostics.log_receive(EVENT_RECEIVE_BROADCAST);
+			// Широковещательно выполняются только функции записи,
+			// остальные запросы отбрасываются без ответа
+			if !self.diagnostics.listen_only && is_broadcast_function(query[1]) {
+				match self.process_function_code(query) {
+					Ok(_)  => self.diagnostics.event_count = self.diagnostics.event_count.wrapping_add(1),
+					Err(e) => eprintln!("Ошибка: {}", e.message),
+				}
+			}
+			self.diagnostics.count_no_response();
+			return None;
+		}
+		self.diagnostics.log_receive(0);
+		let listen_only = self.diagnostics.listen_only;
+		if listen_only && !is_restart(query) {
+			self.diagnostics.count_no_response();
+			return None;
+		}
+		let result = self.process_function_code(query);
+		// Запрос счётчика событий сам его не увеличивает
+		if result.is_ok() && query[1] != MbFunc::GetCommEventCounter as u8 {
+			self.diagnostics.event_count = self.diagnostics.event_count.wrapping_add(1);
+		}
+		if listen_only || self.diagnostics.listen_only {
+			self.diagnostics.count_no_response();
+			return None;
+		}
+		Some(result)
+	}
+
+	// Подфункции диагностики. Ответ - эхо подфункции и данных,
+	// счётчики возвращаются вместо данных
+	pub(super) fn process_diagnostics(&mut self, sub_function: u16, data: u16) -> Result<Vec<u8>, MbExcWithMessage> {
+		if sub_function != DIAG_RETURN_QUERY_DATA && sub_function != DIAG_RESTART_COMMUNICATIONS && data != 0 {
+			return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_DIAG_DATA.into()));
+		}
+		let counters = self.diagnostics.counters;
+		let value = match sub_function {
+			DIAG_RETURN_QUERY_DATA => data,
+			DIAG_RESTART_COMMUNICATIONS => {
+				// 0xFF00 дополнительно очищает журнал событий
+				if data != 0x0000 && data != 0xFF00 {
+					return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_DIAG_DATA.into()));
+				}
+				self.diagnostics.clear();
+				self.diagnostics.listen_only = false;
+				if data == 0xFF00 { self.diagnostics.events.clear(); }
+				self.diagnostics.log_event(EVENT_RESTART);
+				data
+			},
+			DIAG_RETURN_DIAGNOSTIC_REGISTER => self.diagnostics.register,
+			DIAG_FORCE_LISTEN_ONLY => {
+				self.diagnostics.listen_only = true;
+				self.diagnostics.log_event(EVENT_LISTEN_ONLY);
+				data
+			},
+			DIAG_CLEAR_COUNTERS => {
+				self.diagnostics.clear();
+				data
+			},
+			DIAG_BUS_MESSAGE_COUNT        => counters.bus_message,
+			DIAG_BUS_COMM_ERROR_COUNT     => counters.bus_comm_error,
+			DIAG_BUS_EXCEPTION_COUNT      => counters.bus_exception,
+			DIAG_SERVER_MESSAGE_COUNT     => counters.server_message,
+			DIAG_SERVER_NO_RESPONSE_COUNT => counters.server_no_response,
+			DIAG_BUS_CHAR_OVERRUN_COUNT   => counters.bus_char_overrun,
+			DIAG_CLEAR_OVERRUN => {
+				self.diagnostics.counters.bus_char_overrun = 0;
+				data
+			},
+			_ => return Err(MbExcWithMessage::new(MbExc::IllegalFunction, "Неподдерживаемая подфункция диагностики".into())),
+		};
+		let mut odat = Vec::with_capacity(4);
+		odat.extend(&sub_function.to_be_bytes());
+		odat.extend(&value.to_be_bytes());
+		Ok(odat)
+	}
+
+	// Ответ 0x0B: слово состояния и счётчик событий
+	pub(super) fn comm_event_counter(&self) -> Vec<u8> {
+		let mut odat = Vec::with_capacity(4);
+		odat.extend(&COMM_STATUS_READY.to_be_bytes());
+		odat.extend(&self.diagnostics.event_count.to_be_bytes());
+		odat
+	}
+
+	// Ответ 0x0C: слово состояния, счётчик событий, счётчик сообщений на линии
+	// и журнал событий, начиная с последнего
+	pub(super) fn comm_event_log(&self) -> Vec<u8> {
+		let d = &self.diagnostics;
+		let mut odat = Vec::with_capacity(7 + d.events.len());
+		odat.push((6 + d.events.len()) as u8);
+		odat.extend(&COMM_STATUS_READY.to_be_bytes());
+		odat.extend(&d.event_count.to_be_bytes());
+		odat.extend(&d.counters.bus_message.to_be_bytes());
+		odat.extend(d.events.iter());
+		odat
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sub_functions() {
+		let mut d = Device::new();
+		assert_eq!(d.process_function_code(&[1, 0x08, 0x00, 0x00, 0xA5, 0x37]).unwrap(), vec![0x00, 0x00, 0xA5, 0x37]);
+		d.diagnostics_mut().register = 0x1234;
+		d.diagnostics_mut().count_comm_error();
+		assert_eq!(d.process_function_code(&[1, 0x08, 0x00, 0x02, 0x00, 0x00]).unwrap(), vec![0x00, 0x02, 0x12, 0x34]);
+		assert_eq!(d.process_function_code(&[1, 0x08, 0x00, 0x0C, 0x00, 0x00]).unwrap(), vec![0x00, 0x0C, 0x00, 0x01]);
+		d.process_function_code(&[1, 0x08, 0x00, 0x0A, 0x00, 0x00]).unwrap();
+		assert_eq!(d.diagnostics().counters, Counters::default());
+		assert_eq!(d.diagnostics().register, 0);
+
+		assert_eq!(d.process_function_code(&[1, 0x08, 0x00, 0x0B, 0x00, 0x01]).unwrap_err().exc, MbExc::IllegalDataValue);
+		assert_eq!(d.process_function_code(&[1, 0x08, 0x00, 0x01, 0x12, 0x34]).unwrap_err().exc, MbExc::IllegalDataValue);
+		assert_eq!(d.process_function_code(&[1, 0x08, 0x00, 0x03, 0x00, 0x00]).unwrap_err().exc, MbExc::IllegalFunction);
+	}
+
+	#[test]
+	fn listen_only_mode() {
+		let mut d = Device::new();
+		assert!(d.process_bus_query(&[1, 0x08, 0x00, 0x04, 0x00, 0x00]).is_none());
+		assert!(d.diagnostics().listen_only());
+		// Запись в режиме только прослушивания не выполняется
+		assert!(d.process_bus_query(&[1, 0x06, 0x00, 0x00, 0x12, 0x34]).is_none());
+		assert!(d.process_bus_query(&[1, 0x08, 0x00, 0x01, 0x00, 0x00]).is_none());
+		assert!(!d.diagnostics().listen_only());
+		assert_eq!(d.process_bus_query(&[1, 0x03, 0x00, 0x00, 0x00, 0x01]).unwrap().unwrap(), vec![2, 0x00, 0x00]);
+		// Перезапуск связи очистил счётчики
+		assert_eq!(d.diagnostics().counters.server_message, 1);
+	}
+
+	#[test]
+	fn broadcast() {
+		let mut d = Device::new();
+		assert!(d.process_bus_query(&[0, 0x06, 0x00, 0x01, 0x12, 0x34]).is_none());
+		assert!(d.process_bus_query(&[0, 0x03, 0x00, 0x00, 0x00, 0x02]).is_none());
+		assert_eq!(d.process_function_code(&[1, 0x03, 0x00, 0x00, 0x00, 0x02]).unwrap(), vec![4, 0x00, 0x00, 0x12, 0x34]);
+		assert_eq!(d.diagnostics().counters.server_no_response, 2);
+		assert_eq!(d.diagnostics().event_count(), 1);
+		assert_eq!(d.diagnostics().events()[0], EVENT_RECEIVE | EVENT_RECEIVE_BROADCAST);
+	}
+
+	#[test]
+	fn comm_event_log() {
+		let mut d = Device::new();
+		d.process_bus_query(&[1, 0x06, 0x00, 0x00, 0x12, 0x34]).unwrap().unwrap();
+		d.diagnostics_mut().log_send(None);
+		d.process_bus_query(&[1, 0x03, 0x00, 0x00, 0x00, 0x00]).unwrap().unwrap_err();
+		d.diagnostics_mut().log_send(Some(MbExc::IllegalDataValue));
+		assert_eq!(d.process_bus_query(&[1, 0x0B]).unwrap().unwrap(), vec![0x00, 0x00, 0x00, 0x01]);
+		d.diagnostics_mut().log_send(None);
+		assert_eq!(
+			d.process_bus_query(&[1, 0x0C]).unwrap().unwrap(),
+			vec![13, 0x00, 0x00, 0x00, 0x01, 0x00, 0x04, 0x80, 0x40, 0x80, 0x41, 0x80, 0x40, 0x80]
+		);
+
+		for _ in 0..100 { d.diagnostics_mut().log_receive(EVENT_RECEIVE_COMM_ERROR); }
+		assert_eq!(d.diagnostics().events().len(), EVENT_LOG_SIZE);
+		d.process_function_code(&[1, 0x08, 0x00, 0x01, 0xFF, 0x00]).unwrap();
+		assert_eq!(d.diagnostics().events().iter().copied().collect::<Vec<u8>>(), vec![EVENT_RESTART]);
+		assert_eq!(d.diagnostics().event_count(), 0);
+	}
+}
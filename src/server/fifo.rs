@@ -0,0 +1,85 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Очереди FIFO для функции Read FIFO Queue (0x18).
+// Очередь привязана к адресу указателя FIFO в holding registers,
+// значения добавляются устройством (push_fifo) и читаются мастером без удаления
+//------------------------------------------------------------------------------
+use std::collections::VecDeque;
+
+use crate::server::device::Device;
+use crate::server::formal::*;
+
+// Наибольшее количество значений, которое можно вернуть в одном ответе
+pub const MAX_FIFO_COUNT: usize = 31;
+
+impl Device {
+	// Создание пустой очереди. Существующая очередь не изменяется
+	pub fn add_fifo(&mut self, address: u16) {
+		self.fifos.entry(address).or_default();
+	}
+
+	pub fn fifo_addresses(&self) -> Vec<u16> {
+		self.fifos.keys().copied().collect()
+	}
+
+	pub fn fifo(&self, address: u16) -> Option<&VecDeque<u16>> {
+		self.fifos.get(&address)
+	}
+
+	// Доступ к очереди для удаления прочитанных значений и т.п.
+	pub fn fifo_mut(&mut self, address: u16) -> Option<&mut VecDeque<u16>> {
+		self.fifos.get_mut(&address)
+	}
+
+	// Добавление значения в конец очереди
+	pub fn push_fifo(&mut self, address: u16, value: u16) -> Result<(), String> {
+		let fifo = self.fifos.get_mut(&address).ok_or_else(|| format!("очередь FIFO с адресом {} не задана", address))?;
+		fifo.push_back(value);
+		Ok(())
+	}
+
+	// Ответ 0x18: счётчик байт, количество значений в очереди и сами значения
+	pub(super) fn read_fifo_queue(&self, address: u16) -> Result<Vec<u8>, MbExcWithMessage> {
+		let fifo = self.fifos.get(&address).ok_or_else(|| MbExcWithMessage::new(MbExc::IllegalDataAddress, "Очередь FIFO не найдена".into()))?;
+		if fifo.len() > MAX_FIFO_COUNT {
+			return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, "В очереди FIFO больше 31 значения".into()));
+		}
+		let mut odat = Vec::with_capacity(4 + fifo.len() * 2);
+		odat.extend(&((2 + fifo.len() * 2) as u16).to_be_bytes());
+		odat.extend(&(fifo.len() as u16).to_be_bytes());
+		for value in fifo.iter() {
+			odat.extend(&value.to_be_bytes());
+		}
+		Ok(odat)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn read_fifo_queue() {
+		let mut d = Device::new();
+		assert_eq!(d.process_function_code(&[1, 0x18, 0x04, 0xDE]).unwrap_err().exc, MbExc::IllegalDataAddress);
+		d.add_fifo(0x04DE);
+		assert_eq!(d.process_function_code(&[1, 0x18, 0x04, 0xDE]).unwrap(), vec![0x00, 0x02, 0x00, 0x00]);
+		d.push_fifo(0x04DE, 0x01B8).unwrap();
+		d.push_fifo(0x04DE, 0x1284).unwrap();
+		assert_eq!(
+			d.process_function_code(&[1, 0x18, 0x04, 0xDE]).unwrap(),
+			vec![0x00, 0x06, 0x00, 0x02, 0x01, 0xB8, 0x12, 0x84]
+		);
+		assert!(d.push_fifo(0x04DF, 1).is_err());
+
+		for i in 0..30 { d.push_fifo(0x04DE, i).unwrap(); }
+		assert_eq!(d.process_function_code(&[1, 0x18, 0x04, 0xDE]).unwrap_err().exc, MbExc::IllegalDataValue);
+		d.fifo_mut(0x04DE).unwrap().pop_front();
+		assert_eq!(d.process_function_code(&[1, 0x18, 0x04, 0xDE]).unwrap()[..4], [0x00, 64, 0x00, 31]);
+	}
+}
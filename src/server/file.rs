@@ -0,0 +1,66 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Файлы для функций Read File Record (0x14) и Write File Record (0x15).
+// Файл - массив 16-битных записей с номером от 1 до 65535
+//------------------------------------------------------------------------------
+use std::ops::Range;
+
+use crate::server::device::Device;
+use crate::server::formal::*;
+
+// Тип ссылки в подзапросе, единственный допустимый по спецификации
+pub const FILE_REFERENCE_TYPE: u8 = 6;
+// Наибольшее количество записей в файле
+pub const MAX_FILE_RECORDS: usize = 10000;
+// Длина заголовка подзапроса: тип ссылки, номер файла, номер записи, длина
+pub const FILE_SUB_REQUEST_LEN: usize = 7;
+
+impl Device {
+	// Создание файла из size нулевых записей. Существующий файл заменяется
+	pub fn add_file(&mut self, number: u16, size: usize) -> Result<(), String> {
+		if number == 0 {
+			return Err("номер файла должен быть от 1 до 65535".into());
+		}
+		if size > MAX_FILE_RECORDS {
+			return Err(format!("в файле {} больше {} записей", number, MAX_FILE_RECORDS));
+		}
+		self.files.insert(number, vec![0; size]);
+		Ok(())
+	}
+
+	pub fn file_numbers(&self) -> Vec<u16> {
+		self.files.keys().copied().collect()
+	}
+
+	pub fn file(&self, number: u16) -> Option<&[u16]> {
+		self.files.get(&number).map(|f| f.as_slice())
+	}
+
+	pub fn file_mut(&mut self, number: u16) -> Option<&mut [u16]> {
+		self.files.get_mut(&number).map(|f| f.as_mut_slice())
+	}
+
+	// Проверка подзапроса и диапазон записей в файле
+	pub(super) fn file_range(&self, sub_request: &[u8]) -> Result<(u16, Range<usize>), MbExcWithMessage> {
+		let reference_type = sub_request[0];
+		let number = u16::from_be_bytes([sub_request[1], sub_request[2]]);
+		let record = u16::from_be_bytes([sub_request[3], sub_request[4]]) as usize;
+		let length = u16::from_be_bytes([sub_request[5], sub_request[6]]) as usize;
+		if reference_type != FILE_REFERENCE_TYPE {
+			return Err(MbExcWithMessage::new(MbExc::IllegalDataAddress, "Неверный тип ссылки в подзапросе".into()));
+		}
+		if length == 0 {
+			return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_QUANTITY.into()));
+		}
+		let file = self.files.get(&number).ok_or_else(|| MbExcWithMessage::new(MbExc::IllegalDataAddress, "Файл не найден".into()))?;
+		if record + length > file.len() {
+			return Err(MbExcWithMessage::new(MbExc::IllegalDataAddress, STR_INDEX_OUT.into()));
+		}
+		Ok((number, record..record + length))
+	}
+}
@@ -7,6 +7,7 @@
 // Простой сервер Modbus RTU
 // Формальные части программы
 //------------------------------------------------------------------------------
+use std::fmt;
 
 // Расчёт CRC по спецификации Modbus
 pub fn crc(buf: &[u8]) -> u16 {
@@ -27,6 +28,38 @@ pub fn crc(buf: &[u8]) -> u16 {
 	crc
 }
 
+// Расчёт LRC по спецификации Modbus ASCII
+// (дополнение до двух суммы всех байтов без учёта переноса)
+pub fn lrc(buf: &[u8]) -> u8 {
+	let mut sum: u8 = 0;
+	for &e in buf.iter() {
+		sum = sum.wrapping_add(e);
+	}
+	sum.wrapping_neg()
+}
+
+// Кодирование байтов в шестнадцатеричные символы для Modbus ASCII
+pub fn ascii_encode(src: &[u8], dst: &mut Vec<u8>) {
+	const HEX: &[u8; 16] = b"0123456789ABCDEF";
+	for &e in src.iter() {
+		dst.push(HEX[(e >> 4) as usize]);
+		dst.push(HEX[(e & 0x0F) as usize]);
+	}
+}
+
+// Декодирование шестнадцатеричных символов Modbus ASCII в байты.
+// Возвращает None, если длина нечётная или встретился недопустимый символ
+pub fn ascii_decode(src: &[u8]) -> Option<Vec<u8>> {
+	if src.len() % 2 != 0 { return None; }
+	let mut dst = Vec::with_capacity(src.len() / 2);
+	for pair in src.chunks(2) {
+		let hi = (pair[0] as char).to_digit(16)?;
+		let lo = (pair[1] as char).to_digit(16)?;
+		dst.push((hi << 4 | lo) as u8);
+	}
+	Some(dst)
+}
+
 // Упаковка байтов в биты для передачи через Modbus
 pub fn pack_bits(src: &[u8], dst: &mut Vec<u8>) {
 	let mut val: u8 = 0;
@@ -56,8 +89,14 @@ pub fn unpack_bits(src: &[u8], dst: &mut [u8]) {
 	}
 }
 
+// Адрес широковещательного запроса
+pub const BROADCAST_ID: u8 = 0;
+
+// Максимальная длина PDU по спецификации Modbus
+pub const MAX_PDU_LEN: usize = 253;
+
 // Modbus function codes
-#[derive(FromPrimitive)]
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MbFunc {
 	ReadCoils              = 0x01,
 	ReadDiscreteInputs     = 0x02,
@@ -65,13 +104,24 @@ pub enum MbFunc {
 	ReadInputRegisters     = 0x04,
 	WriteSingleCoil        = 0x05,
 	WriteSingleRegister    = 0x06,
+	ReadExceptionStatus    = 0x07,
+	Diagnostics            = 0x08,
+	GetCommEventCounter    = 0x0B,
+	GetCommEventLog        = 0x0C,
 	WriteMultipleCoils     = 0x0F,
 	WriteMultipleRegisters = 0x10,
+	ReportServerId         = 0x11,
+	ReadFileRecord         = 0x14,
+	WriteFileRecord        = 0x15,
+	MaskWriteRegister      = 0x16,
+	ReadWriteMultipleRegisters = 0x17,
+	ReadFifoQueue          = 0x18,
+	EncapsulatedInterfaceTransport = 0x2B,
 }
 
 // Modbus exception codes
 #[repr(u8)]
-#[derive(FromPrimitive)]
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MbExc {
 	IllegalFunction    = 1,
 	IllegalDataAddress = 2,
@@ -84,16 +134,37 @@ pub enum MbExc {
 	GatewayTargetDeviceFailedToRespond = 0xB,
 }
 
+#[derive(Debug)]
 pub struct MbExcWithMessage {
 	pub exc: MbExc,
 	pub message: String,
+	// Вычисленная длина запроса превышает размер буфера приёма
+	pub overrun: bool,
 }
 
+impl fmt::Display for MbExcWithMessage {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:?}: {}", self.exc, self.message)
+	}
+}
+
+impl std::error::Error for MbExcWithMessage {}
+
 impl MbExcWithMessage {
 	pub fn new(exc: MbExc, message: String) -> MbExcWithMessage {
 		MbExcWithMessage {
-			exc: exc,
-			message: message,
+			exc,
+			message,
+			overrun: false,
+		}
+	}
+
+	// Ошибка переполнения буфера приёма при вычислении длины запроса
+	pub fn overrun() -> MbExcWithMessage {
+		MbExcWithMessage {
+			exc: MbExc::SlaveDeviceFailure,
+			message: STR_BUFFER_OVERRUN.into(),
+			overrun: true,
 		}
 	}
 }
@@ -102,6 +173,10 @@ pub const STR_ILLEGAL_FUNCTION: &str = "Недействительный код
 pub const STR_INVALID_QUANTITY: &str = "Неверное количество байт (quantity)";
 pub const STR_INDEX_OUT: &str = "Адрес выходит за допустимые пределы";
 pub const STR_INVALID_BYTE_COUNT: &str = "Значение \"byte count\" не соответствует значению \"quantity\"";
+pub const STR_INVALID_LENGTH: &str = "Длина пакета не соответствует коду функции";
+pub const STR_INVALID_DIAG_DATA: &str = "Недопустимое значение данных подфункции диагностики";
+pub const STR_RESPONSE_TOO_LONG: &str = "Ответ не помещается в пакет";
+pub const STR_BUFFER_OVERRUN: &str = "Вычислена неверная длина пакета";
 
 // Длина области данных для различных функций Modbus RTU.
 // usize::MAX - Размер вычисляется динамически.
@@ -114,24 +189,24 @@ pub const QUERY_LEN: [usize; 0x30] = [
 	5, // 0x04 Read input registers
 	5, // 0x05 Write single coil
 	5, // 0x06 Write single register
-	0, // 0x07
-	0, // 0x08
+	1, // 0x07 Read exception status
+	5, // 0x08 Diagnostics
 	0, // 0x09
 	0, // 0x0A
-	0, // 0x0B
-	0, // 0x0C
+	1, // 0x0B Get comm event counter
+	1, // 0x0C Get comm event log
 	0, // 0x0D
 	0, // 0x0E
 	usize::MAX, // 0x0F Write multiple coils
 	usize::MAX, // 0x10 Write multiple registers
-	0, // 0x11
+	1, // 0x11 Report server id
 	0, // 0x12
 	0, // 0x13
-	0, // 0x14
-	0, // 0x15
-	0, // 0x16
-	0, // 0x17
-	0, // 0x18
+	usize::MAX, // 0x14 Read file record
+	usize::MAX, // 0x15 Write file record
+	7, // 0x16 Mask write register
+	usize::MAX, // 0x17 Read/write multiple registers
+	3, // 0x18 Read FIFO queue
 	0, // 0x19
 	0, // 0x1A
 	0, // 0x1B
@@ -150,10 +225,54 @@ pub const QUERY_LEN: [usize; 0x30] = [
 	0, // 0x28
 	0, // 0x29
 	0, // 0x2A
-	0, // 0x2B
+	4, // 0x2B Encapsulated interface transport (MEI 0x0E Read device identification)
 	0, // 0x2C
 	0, // 0x2D
 	0, // 0x2E
 	0, // 0x2F
 ];
 
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn crc_matches_reference_frame() {
+		// 01 03 00 00 00 0A C5 CD
+		let crc = crc(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]);
+		assert_eq!(crc.to_le_bytes(), [0xC5, 0xCD]);
+	}
+
+	#[test]
+	fn lrc_matches_reference_frame() {
+		// :010300000001FB
+		assert_eq!(lrc(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x01]), 0xFB);
+		assert_eq!(lrc(&[]), 0x00);
+	}
+
+	#[test]
+	fn pack_and_unpack_bits() {
+		let bits = [1, 0, 1, 1, 0, 0, 1, 1, 1];
+		let mut packed = Vec::new();
+		pack_bits(&bits, &mut packed);
+		assert_eq!(packed, vec![0xCD, 0x01]);
+
+		let mut unpacked = [0u8; 9];
+		unpack_bits(&packed, &mut unpacked);
+		assert_eq!(unpacked, bits);
+	}
+
+	#[test]
+	fn ascii_round_trip() {
+		let mut encoded = Vec::new();
+		ascii_encode(&[0x01, 0xAB, 0xF0], &mut encoded);
+		assert_eq!(encoded, b"01ABF0");
+		assert_eq!(ascii_decode(b"01abF0"), Some(vec![0x01, 0xAB, 0xF0]));
+	}
+
+	#[test]
+	fn ascii_decode_rejects_bad_input() {
+		assert_eq!(ascii_decode(b"0"), None);
+		assert_eq!(ascii_decode(b"0G"), None);
+	}
+}
@@ -0,0 +1,138 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Пользовательские функции (коды 65 - 72 и 100 - 110).
+// Обработчик регистрируется вместе с правилом определения длины запроса
+//------------------------------------------------------------------------------
+use std::ops::RangeInclusive;
+
+use crate::server::{ get_query_len, IN_BUF_SIZE };
+use crate::server::device::Device;
+use crate::server::store::DataStore;
+use crate::server::formal::*;
+
+// Коды, зарезервированные спецификацией для пользовательских функций
+pub const USER_FUNCTION_CODES: [RangeInclusive<u8>; 2] = [65..=72, 100..=110];
+
+// Правило определения длины запроса. Длины считаются в байтах после кода функции
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryLen {
+	// Запрос фиксированной длины
+	Fixed(usize),
+	// Счётчик байт в позиции offset, за ним следует указанное им количество байт
+	ByteCount { offset: usize },
+}
+
+impl QueryLen {
+	// Длина кадра RTU, включая адрес устройства и CRC.
+	// usize::MAX, если длину пока определить нельзя
+	pub fn query_len(&self, query: &[u8]) -> Result<usize, MbExcWithMessage> {
+		let len = match *self {
+			QueryLen::Fixed(n) => n + 1 + 1 + 2,
+			QueryLen::ByteCount { offset } => {
+				if query.len() <= 2 + offset { return Ok(usize::MAX); }
+				query[2 + offset] as usize + 2 + offset + 1 + 2
+			},
+		};
+		if len > IN_BUF_SIZE { return Err(MbExcWithMessage::overrun()); }
+		Ok(len)
+	}
+
+	fn min_len(&self) -> usize {
+		match *self {
+			QueryLen::Fixed(n) => n + 1 + 1 + 2,
+			QueryLen::ByteCount { offset } => 2 + offset + 1 + 2,
+		}
+	}
+}
+
+// Обработчик пользовательской функции.
+// request - данные запроса после кода функции, результат - данные ответа после кода функции
+pub trait FunctionHandler: Send {
+	fn handle(&mut self, store: &mut dyn DataStore, request: &[u8]) -> Result<Vec<u8>, MbExcWithMessage>;
+}
+
+impl<F> FunctionHandler for F
+	where F: FnMut(&mut dyn DataStore, &[u8]) -> Result<Vec<u8>, MbExcWithMessage> + Send
+{
+	fn handle(&mut self, store: &mut dyn DataStore, request: &[u8]) -> Result<Vec<u8>, MbExcWithMessage> {
+		self(store, request)
+	}
+}
+
+pub(super) struct UserFunction {
+	pub(super) len:     QueryLen,
+	pub(super) handler: Box<dyn FunctionHandler>,
+}
+
+impl Device {
+	// Регистрация обработчика. Ранее зарегистрированный обработчик кода заменяется
+	pub fn register_function<H>(&mut self, function: u8, len: QueryLen, handler: H) -> Result<(), String>
+		where H: FunctionHandler + 'static
+	{
+		if !USER_FUNCTION_CODES.iter().any(|r| r.contains(&function)) {
+			return Err(format!("код {} не относится к пользовательским функциям", function));
+		}
+		if len.min_len() > IN_BUF_SIZE {
+			return Err(format!("запрос функции {} не помещается в буфер", function));
+		}
+		self.functions.insert(function, UserFunction { len, handler: Box::new(handler) });
+		Ok(())
+	}
+
+	pub fn unregister_function(&mut self, function: u8) -> bool {
+		self.functions.remove(&function).is_some()
+	}
+
+	// Длина кадра RTU с учётом пользовательских функций, см. get_query_len
+	pub fn query_len(&self, query: &[u8]) -> Result<usize, MbExcWithMessage> {
+		if query.len() < 2 { return Ok(usize::MAX); }
+		match self.functions.get(&query[1]) {
+			Some(f) => f.len.query_len(query),
+			None    => get_query_len(query),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::server::store::RegisterTable;
+
+	#[test]
+	fn query_len_rules() {
+		assert_eq!(QueryLen::Fixed(2).query_len(&[1, 65]).unwrap(), 6);
+		let rule = QueryLen::ByteCount { offset: 1 };
+		assert_eq!(rule.query_len(&[1, 65, 0]).unwrap(), usize::MAX);
+		assert_eq!(rule.query_len(&[1, 65, 0, 3]).unwrap(), 3 + 4 + 2);
+		let e = QueryLen::ByteCount { offset: 0 }.query_len(&[1, 65, 0xFF]).unwrap_err();
+		assert_eq!(e.exc, MbExc::SlaveDeviceFailure);
+		assert!(e.overrun);
+	}
+
+	#[test]
+	fn user_function() {
+		let mut d = Device::new();
+		assert_eq!(d.query_len(&[1, 100]).unwrap_err().exc, MbExc::IllegalFunction);
+		assert_eq!(d.process_function_code(&[1, 100, 0x00, 0x02]).unwrap_err().exc, MbExc::IllegalFunction);
+
+		// Сумма holding registers с адреса 0
+		d.register_function(100, QueryLen::Fixed(2), |store: &mut dyn DataStore, request: &[u8]| {
+			let quantity = u16::from_be_bytes([request[0], request[1]]) as usize;
+			let sum: u16 = store.read_registers(RegisterTable::HoldingRegisters, 0, quantity)?.iter().sum();
+			Ok(sum.to_be_bytes().to_vec())
+		}).unwrap();
+		d.store_mut().write_registers(RegisterTable::HoldingRegisters, 0, &[1, 2, 3]).unwrap();
+		assert_eq!(d.query_len(&[1, 100]).unwrap(), 6);
+		assert_eq!(d.process_function_code(&[1, 100, 0x00, 0x03]).unwrap(), vec![0x00, 0x06]);
+		assert_eq!(d.process_function_code(&[1, 100, 0x10, 0x00]).unwrap_err().exc, MbExc::IllegalDataAddress);
+
+		assert!(d.register_function(0x03, QueryLen::Fixed(4), |_: &mut dyn DataStore, _: &[u8]| Ok(vec![])).is_err());
+		assert!(d.unregister_function(100));
+		assert_eq!(d.query_len(&[1, 100]).unwrap_err().exc, MbExc::IllegalFunction);
+	}
+}
@@ -0,0 +1,192 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Идентификация устройства (функция 0x2B, MEI 0x0E Read Device Identification)
+//------------------------------------------------------------------------------
+use std::collections::BTreeMap;
+
+use crate::server::formal::*;
+
+// MEI type для чтения идентификации устройства
+pub const MEI_READ_DEVICE_ID: u8 = 0x0E;
+
+// Стандартные объекты. 0x00 - 0x02 обязательны (basic),
+// 0x03 - 0x7F - regular (0x07 - 0x7F зарезервированы),
+// 0x80 - 0xFF - extended, определяются производителем
+pub const OBJ_VENDOR_NAME:           u8 = 0x00;
+pub const OBJ_PRODUCT_CODE:          u8 = 0x01;
+pub const OBJ_MAJOR_MINOR_REVISION:  u8 = 0x02;
+pub const OBJ_VENDOR_URL:            u8 = 0x03;
+pub const OBJ_PRODUCT_NAME:          u8 = 0x04;
+pub const OBJ_MODEL_NAME:            u8 = 0x05;
+pub const OBJ_USER_APPLICATION_NAME: u8 = 0x06;
+pub const OBJ_FIRST_EXTENDED:        u8 = 0x80;
+
+// Коды способа чтения (Read Device ID code)
+pub const READ_BASIC:      u8 = 0x01;
+pub const READ_REGULAR:    u8 = 0x02;
+pub const READ_EXTENDED:   u8 = 0x03;
+pub const READ_INDIVIDUAL: u8 = 0x04;
+
+// Заголовок ответа: функция, MEI type, код чтения, уровень соответствия,
+// more follows, следующий объект, количество объектов
+const RESPONSE_HEADER_LEN: usize = 7;
+// Максимальная длина значения, при которой объект помещается в ответ
+pub const MAX_OBJECT_LEN: usize = MAX_PDU_LEN - RESPONSE_HEADER_LEN - 2;
+
+#[derive(Debug, Clone)]
+pub struct DeviceIdentification {
+	objects: BTreeMap<u8, Vec<u8>>,
+}
+
+impl DeviceIdentification {
+	pub fn new(vendor_name: &str, product_code: &str, revision: &str) -> DeviceIdentification {
+		let mut objects = BTreeMap::new();
+		objects.insert(OBJ_VENDOR_NAME, vendor_name.as_bytes().to_vec());
+		objects.insert(OBJ_PRODUCT_CODE, product_code.as_bytes().to_vec());
+		objects.insert(OBJ_MAJOR_MINOR_REVISION, revision.as_bytes().to_vec());
+		DeviceIdentification { objects }
+	}
+
+	pub fn set(&mut self, id: u8, value: &[u8]) -> Result<(), String> {
+		if (OBJ_USER_APPLICATION_NAME + 1..OBJ_FIRST_EXTENDED).contains(&id) {
+			return Err(format!("объект 0x{:02X} зарезервирован", id));
+		}
+		if value.len() > MAX_OBJECT_LEN {
+			return Err(format!("значение объекта 0x{:02X} длиннее {} байт", id, MAX_OBJECT_LEN));
+		}
+		self.objects.insert(id, value.to_vec());
+		Ok(())
+	}
+
+	pub fn get(&self, id: u8) -> Option<&[u8]> {
+		self.objects.get(&id).map(|v| v.as_slice())
+	}
+
+	// Уровень соответствия: наибольшая заполненная категория
+	// и поддержка индивидуального доступа (0x80)
+	fn conformity_level(&self) -> u8 {
+		let level = match self.objects.keys().next_back() {
+			Some(&id) if id >= OBJ_FIRST_EXTENDED      => READ_EXTENDED,
+			Some(&id) if id > OBJ_MAJOR_MINOR_REVISION => READ_REGULAR,
+			_ => READ_BASIC,
+		};
+		0x80 | level
+	}
+
+	// Формирование ответа после кода функции.
+	// query - MEI type, код чтения, идентификатор объекта
+	pub fn read(&self, mei_type: u8, code: u8, object_id: u8) -> Result<Vec<u8>, MbExcWithMessage> {
+		if mei_type != MEI_READ_DEVICE_ID {
+			return Err(MbExcWithMessage::new(MbExc::IllegalFunction, "Неподдерживаемый MEI type".into()));
+		}
+		let last_id = match code {
+			READ_BASIC      => OBJ_MAJOR_MINOR_REVISION,
+			READ_REGULAR    => OBJ_FIRST_EXTENDED - 1,
+			READ_EXTENDED   => 0xFF,
+			READ_INDIVIDUAL => object_id,
+			_ => return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, "Неверный код чтения идентификации".into())),
+		};
+
+		let mut odat = vec![mei_type, code, self.conformity_level(), 0x00, 0x00, 0];
+		if code == READ_INDIVIDUAL {
+			let value = self.get(object_id).ok_or_else(|| MbExcWithMessage::new(MbExc::IllegalDataAddress, "Объект идентификации не найден".into()))?;
+			push_object(&mut odat, object_id, value);
+			odat[5] = 1;
+			return Ok(odat);
+		}
+
+		// Для потокового доступа неизвестный объект означает чтение с начала категории
+		let first_id = if object_id <= last_id && self.objects.contains_key(&object_id) { object_id } else { OBJ_VENDOR_NAME };
+		let mut n_objects = 0u8;
+		for (&id, value) in self.objects.range(first_id..=last_id) {
+			// Ответ не помещается в PDU: остальное мастер запросит, начиная с id
+			if 1 + odat.len() + 2 + value.len() > MAX_PDU_LEN {
+				odat[3] = 0xFF;
+				odat[4] = id;
+				break;
+			}
+			push_object(&mut odat, id, value);
+			n_objects += 1;
+		}
+		odat[5] = n_objects;
+		Ok(odat)
+	}
+}
+
+impl Default for DeviceIdentification {
+	fn default() -> DeviceIdentification {
+		DeviceIdentification::new("DDRDmakar", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+	}
+}
+
+fn push_object(odat: &mut Vec<u8>, id: u8, value: &[u8]) {
+	odat.push(id);
+	odat.push(value.len() as u8);
+	odat.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn id() -> DeviceIdentification {
+		let mut id = DeviceIdentification::new("V", "PC", "1.0");
+		id.set(OBJ_PRODUCT_NAME, b"Name").unwrap();
+		id
+	}
+
+	#[test]
+	fn basic_stream() {
+		assert_eq!(
+			id().read(MEI_READ_DEVICE_ID, READ_BASIC, 0).unwrap(),
+			vec![0x0E, 0x01, 0x82, 0x00, 0x00, 3, 0x00, 1, b'V', 0x01, 2, b'P', b'C', 0x02, 3, b'1', b'.', b'0']
+		);
+	}
+
+	#[test]
+	fn regular_stream_from_object() {
+		assert_eq!(
+			id().read(MEI_READ_DEVICE_ID, READ_REGULAR, OBJ_PRODUCT_NAME).unwrap(),
+			vec![0x0E, 0x02, 0x82, 0x00, 0x00, 1, 0x04, 4, b'N', b'a', b'm', b'e']
+		);
+		// Неизвестный объект - чтение с начала
+		assert_eq!(id().read(MEI_READ_DEVICE_ID, READ_REGULAR, OBJ_MODEL_NAME).unwrap()[5], 4);
+	}
+
+	#[test]
+	fn extended_stream_continues() {
+		let mut id = id();
+		for i in 0..4u8 {
+			id.set(OBJ_FIRST_EXTENDED + i, &[i; 100]).unwrap();
+		}
+		assert_eq!(id.conformity_level(), 0x83);
+
+		let first = id.read(MEI_READ_DEVICE_ID, READ_EXTENDED, 0).unwrap();
+		assert_eq!(&first[..6], &[0x0E, 0x03, 0x83, 0xFF, 0x82, 6]);
+		assert!(first.len() < MAX_PDU_LEN);
+		let second = id.read(MEI_READ_DEVICE_ID, READ_EXTENDED, first[4]).unwrap();
+		assert_eq!(&second[..6], &[0x0E, 0x03, 0x83, 0x00, 0x00, 2]);
+	}
+
+	#[test]
+	fn individual_access() {
+		assert_eq!(
+			id().read(MEI_READ_DEVICE_ID, READ_INDIVIDUAL, OBJ_PRODUCT_CODE).unwrap(),
+			vec![0x0E, 0x04, 0x82, 0x00, 0x00, 1, 0x01, 2, b'P', b'C']
+		);
+		assert_eq!(id().read(MEI_READ_DEVICE_ID, READ_INDIVIDUAL, 0x90).unwrap_err().exc, MbExc::IllegalDataAddress);
+	}
+
+	#[test]
+	fn invalid_requests() {
+		assert_eq!(id().read(0x0D, READ_BASIC, 0).unwrap_err().exc, MbExc::IllegalFunction);
+		assert_eq!(id().read(MEI_READ_DEVICE_ID, 0x05, 0).unwrap_err().exc, MbExc::IllegalDataValue);
+		assert!(id().set(0x10, b"x").is_err());
+		assert!(id().set(0x80, &[0; MAX_OBJECT_LEN + 1]).is_err());
+	}
+}
@@ -0,0 +1,658 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Загрузка начального образа таблиц из файла
+//
+// Формат файла - TOML. Для каждой таблицы (discrete_inputs, coils,
+// input_registers, holding_registers) задаётся список блоков.
+// Блок начинается с адреса address и содержит либо массив values,
+// либо одно значение value, повторённое count раз:
+//
+//     [[coils]]
+//     address = 0
+//     values = [1, 0, 1, 1]
+//
+//     [[holding_registers]]
+//     address = 100
+//     values = [0x1234, 500, 65535]
+//
+//     [[input_registers]]
+//     address = 0
+//     value = 7
+//     count = 16
+//
+// Значения битов - 0 или 1, значения регистров - от 0 до 65535.
+// Адреса, не упомянутые в файле, остаются нулевыми.
+//
+// Необязательная секция layout задаёт начальный адрес и размер таблиц
+// (по умолчанию - 1024 ячейки с адреса 0, не более 65536 ячеек).
+// Вместо одного блока можно указать список; адреса вне блоков
+// считаются неопределёнными (исключение IllegalDataAddress):
+//
+//     [layout]
+//     coils = { size = 65536 }
+//     holding_registers = [
+//         { start = 0, size = 100 },
+//         { start = 1000, size = 50 },
+//         { start = 40000, size = 101 },
+//     ]
+//
+// Необязательная секция identification задаёт объекты идентификации
+// устройства (функция 0x2B / 0x0E). Расширенные объекты 0x80 - 0xFF
+// перечисляются в identification.objects:
+//
+//     [identification]
+//     vendor_name = "ACME"
+//     product_code = "PX-100"
+//     revision = "2.1"
+//     product_name = "Pump controller"
+//
+//     [identification.objects]
+//     0x80 = "serial 000123"
+//
+// Секция server_id задаёт ответ Report Server ID (0x11): идентификатор
+// (строка или массив байт) и индикатор работы. Секция exception_status
+// назначает битам 0 - 7 ответа Read Exception Status (0x07) адреса coils:
+//
+//     [server_id]
+//     id = [0x50, 0x58, 0x01]
+//     run = true
+//
+//     [exception_status]
+//     coils = [100, 101, 102]
+//
+// Блоки fifo создают очереди Read FIFO Queue (0x18) по адресу указателя,
+// необязательный массив values задаёт начальное содержимое очереди:
+//
+//     [[fifo]]
+//     address = 0x04DE
+//     values = [440, 4740]
+//
+// Блоки file создают файлы для Read/Write File Record (0x14, 0x15).
+// Размер size (не более 10000 записей) по умолчанию равен длине records,
+// записи после records остаются нулевыми:
+//
+//     [[file]]
+//     number = 4
+//     size = 100
+//     records = [0x06AF, 0x04BE]
+//------------------------------------------------------------------------------
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::server::device::Device;
+use crate::server::store::{ BitTable, RegisterTable, Layout, TableLayout, TableMap };
+use crate::server::identification::*;
+use crate::server::status::{ ServerId, N_EXCEPTION_STATUS_BITS };
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Image {
+	#[serde(default, skip_serializing_if = "LayoutSection::is_empty")]
+	pub layout:            LayoutSection,
+	#[serde(default, skip_serializing_if = "IdentificationSection::is_empty")]
+	pub identification:    IdentificationSection,
+	#[serde(default, skip_serializing_if = "ServerIdSection::is_empty")]
+	pub server_id:         ServerIdSection,
+	#[serde(default, skip_serializing_if = "ExceptionStatusSection::is_empty")]
+	pub exception_status:  ExceptionStatusSection,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub discrete_inputs:   Vec<Block>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub coils:             Vec<Block>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub input_registers:   Vec<Block>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub holding_registers: Vec<Block>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub fifo:              Vec<FifoBlock>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub file:              Vec<FileBlock>,
+}
+
+// Размещение таблиц. Не указанные таблицы сохраняют размещение по умолчанию
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayoutSection {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub discrete_inputs:   Option<TableMapSection>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub coils:             Option<TableMapSection>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub input_registers:   Option<TableMapSection>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub holding_registers: Option<TableMapSection>,
+}
+
+// Объекты идентификации. Не указанные объекты сохраняют значения по умолчанию
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdentificationSection {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub vendor_name:           Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub product_code:          Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub revision:              Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub vendor_url:            Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub product_name:          Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub model_name:            Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub user_application_name: Option<String>,
+	// Ключ - номер объекта, десятичный или шестнадцатеричный с префиксом 0x
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub objects:               BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerIdSection {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub id:  Option<ServerIdBytes>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub run: Option<bool>,
+}
+
+// Идентификатор строкой или массивом байт
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ServerIdBytes {
+	Text(String),
+	Bytes(Vec<u8>),
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExceptionStatusSection {
+	// Адрес coil для каждого бита, начиная с младшего
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub coils: Vec<u16>,
+}
+
+// Один блок или список блоков таблицы
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum TableMapSection {
+	Single(TableLayout),
+	Blocks(Vec<TableLayout>),
+}
+
+impl TableMapSection {
+	pub fn to_map(&self) -> Result<TableMap, String> {
+		match self {
+			TableMapSection::Single(b) => TableMap::new(&[*b]),
+			TableMapSection::Blocks(b) => TableMap::new(b),
+		}
+	}
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Block {
+	pub address: i64,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub values:  Option<Vec<i64>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub value:   Option<i64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub count:   Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FifoBlock {
+	pub address: u16,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub values:  Vec<u16>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileBlock {
+	pub number:  u16,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub size:    Option<usize>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub records: Vec<u16>,
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+	Io(io::Error),
+	Parse(toml::de::Error),
+	// Неверно описан блок (нет значений, одновременно values и value и т.п.)
+	InvalidBlock { table: &'static str, address: i64, message: &'static str },
+	// Блок выходит за пределы таблицы
+	AddressOutOfRange { table: &'static str, address: i64, count: i64 },
+	// Значение не помещается в ячейку таблицы
+	InvalidValue { table: &'static str, address: i64, value: i64 },
+	// Неверное размещение таблицы
+	InvalidLayout { table: &'static str, message: String },
+	// Неверно задан объект идентификации
+	InvalidIdentification { message: String },
+	// Неверно задана секция server_id или exception_status
+	InvalidStatus { section: &'static str, message: String },
+	// Неверно описан файл записей
+	InvalidFile { number: u16, message: String },
+}
+
+impl fmt::Display for ImageError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ImageError::Io(e) => write!(f, "ошибка чтения: {}", e),
+			ImageError::Parse(e) => write!(f, "ошибка разбора: {}", e),
+			ImageError::InvalidBlock { table, address, message } =>
+				write!(f, "{}, блок с адресом {}: {}", table, address, message),
+			ImageError::AddressOutOfRange { table, address, count } =>
+				write!(f, "{}, блок с адресом {} и длиной {} выходит за пределы таблицы", table, address, count),
+			ImageError::InvalidValue { table, address, value } =>
+				write!(f, "{}, адрес {}: недопустимое значение {}", table, address, value),
+			ImageError::InvalidLayout { table, message } =>
+				write!(f, "layout.{}: {}", table, message),
+			ImageError::InvalidIdentification { message } =>
+				write!(f, "identification: {}", message),
+			ImageError::InvalidStatus { section, message } =>
+				write!(f, "{}: {}", section, message),
+			ImageError::InvalidFile { number, message } =>
+				write!(f, "файл {}: {}", number, message),
+		}
+	}
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<io::Error> for ImageError {
+	fn from(e: io::Error) -> ImageError { ImageError::Io(e) }
+}
+
+impl From<toml::de::Error> for ImageError {
+	fn from(e: toml::de::Error) -> ImageError { ImageError::Parse(e) }
+}
+
+impl Image {
+	pub fn load(path: &Path) -> Result<Image, ImageError> {
+		let text = fs::read_to_string(path)?;
+		Image::parse(&text)
+	}
+
+	pub fn parse(text: &str) -> Result<Image, ImageError> {
+		Ok(toml::from_str(text)?)
+	}
+
+	// Размещение таблиц с учётом секции layout
+	pub fn layout(&self, base: Layout) -> Result<Layout, ImageError> {
+		let mut layout = base;
+		let tables = [
+			("discrete_inputs", &self.layout.discrete_inputs, &mut layout.discrete_inputs),
+			("coils", &self.layout.coils, &mut layout.coils),
+			("input_registers", &self.layout.input_registers, &mut layout.input_registers),
+			("holding_registers", &self.layout.holding_registers, &mut layout.holding_registers),
+		];
+		for (table, src, dst) in tables {
+			if let Some(section) = src {
+				*dst = section.to_map().map_err(|message| ImageError::InvalidLayout { table, message })?;
+			}
+		}
+		Ok(layout)
+	}
+
+	// Идентификация устройства с учётом секции identification
+	pub fn identification(&self, base: DeviceIdentification) -> Result<DeviceIdentification, ImageError> {
+		let mut id = base;
+		let section = &self.identification;
+		let named = [
+			(OBJ_VENDOR_NAME, &section.vendor_name),
+			(OBJ_PRODUCT_CODE, &section.product_code),
+			(OBJ_MAJOR_MINOR_REVISION, &section.revision),
+			(OBJ_VENDOR_URL, &section.vendor_url),
+			(OBJ_PRODUCT_NAME, &section.product_name),
+			(OBJ_MODEL_NAME, &section.model_name),
+			(OBJ_USER_APPLICATION_NAME, &section.user_application_name),
+		];
+		for (object, value) in named {
+			if let Some(v) = value {
+				id.set(object, v.as_bytes()).map_err(|message| ImageError::InvalidIdentification { message })?;
+			}
+		}
+		for (key, value) in section.objects.iter() {
+			let object = match key.strip_prefix("0x").or_else(|| key.strip_prefix("0X")) {
+				Some(hex) => u8::from_str_radix(hex, 16),
+				None      => key.parse::<u8>(),
+			}.map_err(|_| ImageError::InvalidIdentification { message: format!("неверный номер объекта \"{}\"", key) })?;
+			if object < OBJ_FIRST_EXTENDED {
+				return Err(ImageError::InvalidIdentification { message: format!("объект 0x{:02X} не является расширенным", object) });
+			}
+			id.set(object, value.as_bytes()).map_err(|message| ImageError::InvalidIdentification { message })?;
+		}
+		Ok(id)
+	}
+}
+
+impl Image {
+	// Ответ Report Server ID с учётом секции server_id
+	pub fn server_id(&self, base: ServerId) -> Result<ServerId, ImageError> {
+		let id = match &self.server_id.id {
+			Some(ServerIdBytes::Text(t))  => t.as_bytes(),
+			Some(ServerIdBytes::Bytes(b)) => b.as_slice(),
+			None => base.id(),
+		};
+		let run = self.server_id.run.unwrap_or(base.run);
+		ServerId::new(id, run).map_err(|message| ImageError::InvalidStatus { section: "server_id", message })
+	}
+
+	// Адреса coils для битов Read Exception Status, None - секция не задана
+	pub fn exception_coils(&self) -> Result<Option<[Option<u16>; N_EXCEPTION_STATUS_BITS]>, ImageError> {
+		let coils = &self.exception_status.coils;
+		if coils.is_empty() { return Ok(None); }
+		exception_coils(coils).map(Some)
+			.map_err(|message| ImageError::InvalidStatus { section: "exception_status", message })
+	}
+}
+
+// Назначение адресов coils битам состояния исключений, начиная с младшего
+pub fn exception_coils(coils: &[u16]) -> Result<[Option<u16>; N_EXCEPTION_STATUS_BITS], String> {
+	if coils.len() > N_EXCEPTION_STATUS_BITS {
+		return Err(format!("задано больше {} coils", N_EXCEPTION_STATUS_BITS));
+	}
+	let mut map = [None; N_EXCEPTION_STATUS_BITS];
+	for (bit, &address) in coils.iter().enumerate() {
+		map[bit] = Some(address);
+	}
+	Ok(map)
+}
+
+impl ServerIdSection {
+	pub fn is_empty(&self) -> bool {
+		self.id.is_none() && self.run.is_none()
+	}
+}
+
+impl ExceptionStatusSection {
+	pub fn is_empty(&self) -> bool {
+		self.coils.is_empty()
+	}
+}
+
+impl IdentificationSection {
+	pub fn is_empty(&self) -> bool {
+		self.vendor_name.is_none() && self.product_code.is_none() && self.revision.is_none()
+			&& self.vendor_url.is_none() && self.product_name.is_none() && self.model_name.is_none()
+			&& self.user_application_name.is_none() && self.objects.is_empty()
+	}
+}
+
+impl LayoutSection {
+	pub fn is_empty(&self) -> bool {
+		self.discrete_inputs.is_none() && self.coils.is_none()
+			&& self.input_registers.is_none() && self.holding_registers.is_none()
+	}
+}
+
+impl Block {
+	// Развёрнутый список значений блока
+	fn expand(&self, table: &'static str) -> Result<Vec<i64>, ImageError> {
+		match (&self.values, self.value, self.count) {
+			(Some(v), None, None) => Ok(v.clone()),
+			(None, Some(v), Some(n)) if n >= 0 => {
+				// Диапазон проверяется до выделения памяти под значения
+				if self.address < 0 || n > 0x10000 - self.address {
+					return Err(ImageError::AddressOutOfRange { table, address: self.address, count: n });
+				}
+				Ok(vec![v; n as usize])
+			},
+			(None, Some(v), None) => Ok(vec![v]),
+			(None, Some(_), Some(_)) => Err(ImageError::InvalidBlock { table, address: self.address, message: "count не может быть отрицательным" }),
+			(None, None, _) => Err(ImageError::InvalidBlock { table, address: self.address, message: "не заданы values или value" }),
+			(Some(_), _, _) => Err(ImageError::InvalidBlock { table, address: self.address, message: "values нельзя использовать вместе с value и count" }),
+		}
+	}
+}
+
+// Проверка блока: адреса должны помещаться в 16 бит, значения - не превышать max
+fn check_block(table: &'static str, b: &Block, max: i64) -> Result<(u16, Vec<i64>), ImageError> {
+	let values = b.expand(table)?;
+	let count = values.len() as i64;
+	if b.address < 0 || b.address > 0x10000 - count {
+		return Err(ImageError::AddressOutOfRange { table, address: b.address, count });
+	}
+	for (i, &v) in values.iter().enumerate() {
+		if v < 0 || v > max {
+			return Err(ImageError::InvalidValue { table, address: b.address + i as i64, value: v });
+		}
+	}
+	Ok((b.address as u16, values))
+}
+
+impl Device {
+	// Загрузка начальных значений таблиц из файла
+	pub fn load_image(&mut self, path: &Path) -> Result<(), ImageError> {
+		let image = Image::load(path)?;
+		self.apply_image(&image)
+	}
+
+	// Блоки, попадающие на неопределённые адреса, считаются ошибкой
+	pub fn apply_image(&mut self, image: &Image) -> Result<(), ImageError> {
+		let bit_tables = [
+			("discrete_inputs", &image.discrete_inputs, BitTable::DiscreteInputs),
+			("coils", &image.coils, BitTable::Coils),
+		];
+		for &(name, blocks, table) in bit_tables.iter() {
+			for b in blocks.iter() {
+				let (address, values) = check_block(name, b, 1)?;
+				let values: Vec<u8> = values.iter().map(|&v| v as u8).collect();
+				self.store.write_bits(table, address, &values)
+					.map_err(|_| ImageError::AddressOutOfRange { table: name, address: b.address, count: values.len() as i64 })?;
+			}
+		}
+		let register_tables = [
+			("input_registers", &image.input_registers, RegisterTable::InputRegisters),
+			("holding_registers", &image.holding_registers, RegisterTable::HoldingRegisters),
+		];
+		for &(name, blocks, table) in register_tables.iter() {
+			for b in blocks.iter() {
+				let (address, values) = check_block(name, b, 0xFFFF)?;
+				let values: Vec<u16> = values.iter().map(|&v| v as u16).collect();
+				self.store.write_registers(table, address, &values)
+					.map_err(|_| ImageError::AddressOutOfRange { table: name, address: b.address, count: values.len() as i64 })?;
+			}
+		}
+		for f in image.fifo.iter() {
+			self.add_fifo(f.address);
+			let fifo = self.fifo_mut(f.address).unwrap();
+			fifo.extend(f.values.iter());
+		}
+		for f in image.file.iter() {
+			let size = f.size.unwrap_or(f.records.len());
+			if f.records.len() > size {
+				return Err(ImageError::InvalidFile { number: f.number, message: "записей больше, чем size".into() });
+			}
+			self.add_file(f.number, size).map_err(|message| ImageError::InvalidFile { number: f.number, message })?;
+			self.file_mut(f.number).unwrap()[..f.records.len()].copy_from_slice(&f.records);
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn apply_blocks_to_tables() {
+		let image = Image::parse("
+			[[coils]]
+			address = 2
+			values = [1, 0, 1]
+
+			[[holding_registers]]
+			address = 10
+			value = 0x1234
+			count = 3
+		").unwrap();
+		let mut d = Device::new();
+		d.apply_image(&image).unwrap();
+		assert_eq!(d.store_mut().read_bits(BitTable::Coils, 0, 6).unwrap(), vec![0, 0, 1, 0, 1, 0]);
+		assert_eq!(
+			d.store_mut().read_registers(RegisterTable::HoldingRegisters, 9, 5).unwrap(),
+			vec![0, 0x1234, 0x1234, 0x1234, 0]
+		);
+	}
+
+	#[test]
+	fn out_of_range_address() {
+		let image = Image::parse("[[input_registers]]\naddress = 1023\nvalues = [1, 2]").unwrap();
+		match Device::new().apply_image(&image) {
+			Err(ImageError::AddressOutOfRange { table: "input_registers", address: 1023, count: 2 }) => {},
+			other => panic!("{:?}", other),
+		}
+	}
+
+	#[test]
+	fn huge_count() {
+		let image = Image::parse("[[coils]]\naddress = 0\nvalue = 1\ncount = 4000000000").unwrap();
+		match Device::new().apply_image(&image) {
+			Err(ImageError::AddressOutOfRange { table: "coils", address: 0, count: 4000000000 }) => {},
+			other => panic!("{:?}", other),
+		}
+		let image = Image::parse("[[coils]]\naddress = 9223372036854775807\nvalues = [1]").unwrap();
+		assert!(matches!(Device::new().apply_image(&image), Err(ImageError::AddressOutOfRange { .. })));
+	}
+
+	#[test]
+	fn invalid_values() {
+		let image = Image::parse("[[discrete_inputs]]\naddress = 0\nvalues = [0, 2]").unwrap();
+		match Device::new().apply_image(&image) {
+			Err(ImageError::InvalidValue { address: 1, value: 2, .. }) => {},
+			other => panic!("{:?}", other),
+		}
+		let image = Image::parse("[[holding_registers]]\naddress = 0\nvalue = 65536").unwrap();
+		assert!(matches!(Device::new().apply_image(&image), Err(ImageError::InvalidValue { .. })));
+		let image = Image::parse("[[coils]]\naddress = 0").unwrap();
+		assert!(matches!(Device::new().apply_image(&image), Err(ImageError::InvalidBlock { .. })));
+		assert!(matches!(Image::parse("[[coils]]\naddres = 0"), Err(ImageError::Parse(_))));
+	}
+
+	#[test]
+	fn layout_section() {
+		let image = Image::parse("
+			[layout]
+			holding_registers = { start = 40000, size = 100 }
+			coils = { size = 65536 }
+		").unwrap();
+		let layout = image.layout(Layout::default()).unwrap();
+		assert_eq!(layout.holding_registers, TableMap::single(40000, 100));
+		assert_eq!(layout.coils, TableMap::single(0, 65536));
+		assert_eq!(layout.input_registers, Layout::default().input_registers);
+
+		let image = Image::parse("
+			[layout]
+			input_registers = [{ start = 1000, size = 50 }, { size = 100 }]
+		").unwrap();
+		let layout = image.layout(Layout::default()).unwrap();
+		assert_eq!(layout.input_registers.blocks(), &[
+			TableLayout { start: 0, size: 100 },
+			TableLayout { start: 1000, size: 50 },
+		]);
+
+		let image = Image::parse("[layout]\ncoils = { start = 1, size = 65536 }").unwrap();
+		assert!(matches!(image.layout(Layout::default()), Err(ImageError::InvalidLayout { table: "coils", .. })));
+		let image = Image::parse("[layout]\ncoils = [{ size = 10 }, { start = 5, size = 10 }]").unwrap();
+		assert!(matches!(image.layout(Layout::default()), Err(ImageError::InvalidLayout { table: "coils", .. })));
+	}
+
+	#[test]
+	fn identification_section() {
+		let image = Image::parse("
+			[identification]
+			vendor_name = \"ACME\"
+			product_name = \"Pump\"
+
+			[identification.objects]
+			0x80 = \"serial\"
+			129 = \"x\"
+		").unwrap();
+		let id = image.identification(DeviceIdentification::new("V", "PC", "1.0")).unwrap();
+		assert_eq!(id.get(OBJ_VENDOR_NAME), Some(&b"ACME"[..]));
+		assert_eq!(id.get(OBJ_PRODUCT_CODE), Some(&b"PC"[..]));
+		assert_eq!(id.get(OBJ_PRODUCT_NAME), Some(&b"Pump"[..]));
+		assert_eq!(id.get(0x80), Some(&b"serial"[..]));
+		assert_eq!(id.get(0x81), Some(&b"x"[..]));
+
+		let image = Image::parse("[identification.objects]\n0x10 = \"x\"").unwrap();
+		assert!(matches!(image.identification(DeviceIdentification::default()), Err(ImageError::InvalidIdentification { .. })));
+		let image = Image::parse("[identification.objects]\nabc = \"x\"").unwrap();
+		assert!(matches!(image.identification(DeviceIdentification::default()), Err(ImageError::InvalidIdentification { .. })));
+	}
+
+	#[test]
+	fn server_id_and_exception_status_sections() {
+		let image = Image::parse("
+			[server_id]
+			id = [0x50, 0x58, 0x01]
+			run = false
+
+			[exception_status]
+			coils = [100, 101]
+		").unwrap();
+		assert_eq!(image.server_id(ServerId::default()).unwrap(), ServerId::new(&[0x50, 0x58, 0x01], false).unwrap());
+		assert_eq!(image.exception_coils().unwrap().unwrap()[..3], [Some(100), Some(101), None]);
+
+		let image = Image::parse("[server_id]\nid = \"PX\"").unwrap();
+		assert_eq!(image.server_id(ServerId::default()).unwrap(), ServerId::new(b"PX", true).unwrap());
+		assert!(Image::default().exception_coils().unwrap().is_none());
+		let image = Image::parse("[exception_status]\ncoils = [0, 1, 2, 3, 4, 5, 6, 7, 8]").unwrap();
+		assert!(matches!(image.exception_coils(), Err(ImageError::InvalidStatus { section: "exception_status", .. })));
+	}
+
+	#[test]
+	fn fifo_blocks() {
+		let image = Image::parse("
+			[[fifo]]
+			address = 0x04DE
+			values = [440, 4740]
+
+			[[fifo]]
+			address = 10
+		").unwrap();
+		let mut d = Device::new();
+		d.apply_image(&image).unwrap();
+		assert_eq!(d.fifo_addresses(), vec![10, 0x04DE]);
+		assert_eq!(d.fifo(0x04DE).unwrap().iter().copied().collect::<Vec<u16>>(), vec![440, 4740]);
+		assert!(d.fifo(10).unwrap().is_empty());
+	}
+
+	#[test]
+	fn file_blocks() {
+		let image = Image::parse("
+			[[file]]
+			number = 4
+			size = 4
+			records = [1, 2]
+
+			[[file]]
+			number = 5
+			records = [7]
+		").unwrap();
+		let mut d = Device::new();
+		d.apply_image(&image).unwrap();
+		assert_eq!(d.file(4).unwrap(), &[1, 2, 0, 0]);
+		assert_eq!(d.file(5).unwrap(), &[7]);
+
+		let image = Image::parse("[[file]]\nnumber = 1\nsize = 1\nrecords = [1, 2]").unwrap();
+		assert!(matches!(Device::new().apply_image(&image), Err(ImageError::InvalidFile { number: 1, .. })));
+		let image = Image::parse("[[file]]\nnumber = 0\nsize = 1").unwrap();
+		assert!(matches!(Device::new().apply_image(&image), Err(ImageError::InvalidFile { number: 0, .. })));
+	}
+}
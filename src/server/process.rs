@@ -9,164 +9,323 @@
 //------------------------------------------------------------------------------
 use byteorder::{ ByteOrder, BigEndian };
 
-use crate::server::Server;
-use crate::server::{ N_DISCRETE_INPUTS, N_COILS, N_INPUT_REGISTERS, N_HOLDING_REGISTERS };
+use crate::server::device::Device;
+use crate::server::store::{ BitTable, RegisterTable };
 use crate::server::formal::*;
+use crate::server::file::{ FILE_REFERENCE_TYPE, FILE_SUB_REQUEST_LEN };
 
-impl Server {
-	pub(super) fn process_function_code(&mut self) -> Result<Vec<u8>, MbExcWithMessage> {
-		let function: u8 = self.query[1];
+impl Device {
+	// Обработка запроса. query - пакет без CRC, начиная с адреса устройства
+	pub fn process_function_code(&mut self, query: &[u8]) -> Result<Vec<u8>, MbExcWithMessage> {
+		let function: u8 = query[1];
+		// Пользовательские функции. Обработчик может записывать в таблицы,
+		// поэтому каждый успешный вызов считается записью
+		if let Some(f) = self.functions.get_mut(&function) {
+			let result = f.handler.handle(self.store.as_mut(), &query[2..]);
+			if result.is_ok() { self.mark_written(); }
+			return result;
+		}
 		let function_enum = num::FromPrimitive::from_u8(function);
 		let mut odat = Vec::with_capacity(64);
 		
 		match function_enum { // TODO return error packets
 			Some(MbFunc::ReadCoils) => {
 				println!("ReadCoils");
-				let offset    = BigEndian::read_u16(&self.query[2..4]) as usize;
-				let quantity  = BigEndian::read_u16(&self.query[4..6]) as usize;
+				let offset    = BigEndian::read_u16(&query[2..4]);
+				let quantity  = BigEndian::read_u16(&query[4..6]) as usize;
 				dbg!(offset);
 				dbg!(quantity);
 
 				if quantity == 0 || quantity > 2000 { return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_QUANTITY.into())); }
-				if offset + quantity >= N_COILS { return Err(MbExcWithMessage::new(MbExc::IllegalDataAddress, STR_INDEX_OUT.into())); }
+				let bits = self.store.read_bits(BitTable::Coils, offset, quantity)?;
 				
 				let n_bytes = (quantity as f32 / 8_f32).ceil() as usize;
 				
 				odat.push(n_bytes as u8);
-				pack_bits(&self.coils[offset..offset + quantity], &mut odat);
+				pack_bits(&bits, &mut odat);
 				
-				return Ok(odat);
+				Ok(odat)
 			},
 			
 			Some(MbFunc::ReadDiscreteInputs) => {
 				println!("ReadDiscreteInputs");
-				let offset    = BigEndian::read_u16(&self.query[2..4]) as usize;
-				let quantity  = BigEndian::read_u16(&self.query[4..6]) as usize;
+				let offset    = BigEndian::read_u16(&query[2..4]);
+				let quantity  = BigEndian::read_u16(&query[4..6]) as usize;
 				dbg!(offset);
 				dbg!(quantity);
 
 				if quantity == 0 || quantity > 2000 { return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_QUANTITY.into())); }
-				if offset + quantity >= N_DISCRETE_INPUTS { return Err(MbExcWithMessage::new(MbExc::IllegalDataAddress, STR_INDEX_OUT.into())); }
+				let bits = self.store.read_bits(BitTable::DiscreteInputs, offset, quantity)?;
 				
 				let n_bytes = (quantity as f32 / 8_f32).ceil() as usize;
 				
 				odat.push(n_bytes as u8);
-				pack_bits(&self.discrete_input[offset..offset + quantity], &mut odat);
+				pack_bits(&bits, &mut odat);
 				
-				return Ok(odat);
+				Ok(odat)
 			},
 			
 			Some(MbFunc::ReadHoldingRegisters) => {
 				println!("ReadHoldingRegisters");
-				let offset    = BigEndian::read_u16(&self.query[2..4]) as usize;
-				let quantity  = BigEndian::read_u16(&self.query[4..6]) as usize;
+				let offset    = BigEndian::read_u16(&query[2..4]);
+				let quantity  = BigEndian::read_u16(&query[4..6]) as usize;
 				dbg!(offset);
 				dbg!(quantity);
 				let byte_count = quantity * 2;
 
 				if quantity == 0 || quantity > 125 { return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_QUANTITY.into())); }
-				if offset + quantity >= N_HOLDING_REGISTERS { return Err(MbExcWithMessage::new(MbExc::IllegalDataAddress, STR_INDEX_OUT.into())); }
+				let registers = self.store.read_registers(RegisterTable::HoldingRegisters, offset, quantity)?;
 				
 				odat.push(byte_count as u8);
 				let tlen = odat.len();
 				odat.resize(tlen + byte_count, 0);
-				BigEndian::write_u16_into(
-					&self.holding_registers[offset..offset + quantity],
-					&mut odat[tlen..]
-				);
-				return Ok(odat);
+				BigEndian::write_u16_into(&registers, &mut odat[tlen..]);
+				Ok(odat)
 			},
 
 			Some(MbFunc::ReadInputRegisters) => {
 				println!("ReadInputRegisters");
-				let offset    = BigEndian::read_u16(&self.query[2..4]) as usize;
-				let quantity  = BigEndian::read_u16(&self.query[4..6]) as usize;
+				let offset    = BigEndian::read_u16(&query[2..4]);
+				let quantity  = BigEndian::read_u16(&query[4..6]) as usize;
 				dbg!(offset);
 				dbg!(quantity);
 				let byte_count = quantity * 2;
 
 				if quantity == 0 || quantity > 125 { return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_QUANTITY.into())); }
-				if offset + quantity >= N_INPUT_REGISTERS { return Err(MbExcWithMessage::new(MbExc::IllegalDataAddress, STR_INDEX_OUT.into())); }
+				let registers = self.store.read_registers(RegisterTable::InputRegisters, offset, quantity)?;
 				
 				odat.push(byte_count as u8);
 				let tlen = odat.len();
 				odat.resize(tlen + byte_count, 0);
-				BigEndian::write_u16_into(
-					&self.input_registers[offset..offset + quantity],
-					&mut odat[tlen..]
-				);
-				return Ok(odat);
+				BigEndian::write_u16_into(&registers, &mut odat[tlen..]);
+				Ok(odat)
 			},
 
 			Some(MbFunc::WriteSingleCoil) => {
 				println!("WriteSingleCoil");
-				let offset = BigEndian::read_u16(&self.query[2..4]) as usize;
-				let value = BigEndian::read_u16(&self.query[4..6]);
+				let offset = BigEndian::read_u16(&query[2..4]);
+				let value = BigEndian::read_u16(&query[4..6]);
 				dbg!(offset);
 				dbg!(value);
 
-				if offset >= N_COILS { return Err(MbExcWithMessage::new(MbExc::IllegalDataAddress, STR_INDEX_OUT.into())); }
 				if value != 0x0000 && value != 0xFF00 { return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, "Недействительное значение coil".into())); }
 
-				self.coils[offset] = if value == 0 { 0 } else { 1 };
-				odat.extend(&(offset as u16).to_be_bytes());
-				odat.extend(&(value).to_be_bytes());
-				return Ok(odat);
+				self.store.write_bits(BitTable::Coils, offset, &[if value == 0 { 0 } else { 1 }])?;
+				self.mark_written();
+				odat.extend(&offset.to_be_bytes());
+				odat.extend(&value.to_be_bytes());
+				Ok(odat)
 			},
 
 			Some(MbFunc::WriteSingleRegister) => {
 				println!("WriteSingleRegister");
-				let offset = BigEndian::read_u16(&self.query[2..4]) as usize;
-				let value = BigEndian::read_u16(&self.query[4..6]);
+				let offset = BigEndian::read_u16(&query[2..4]);
+				let value = BigEndian::read_u16(&query[4..6]);
 				dbg!(offset);
 				dbg!(value);
 
-				if offset >= N_HOLDING_REGISTERS { return Err(MbExcWithMessage::new(MbExc::IllegalDataAddress, STR_INDEX_OUT.into())); }
+				self.store.write_registers(RegisterTable::HoldingRegisters, offset, &[value])?;
+				self.mark_written();
+				odat.extend(&offset.to_be_bytes());
+				odat.extend(&value.to_be_bytes());
+				Ok(odat)
+			},
+
+			Some(MbFunc::ReadExceptionStatus) => {
+				println!("ReadExceptionStatus");
+				self.read_exception_status()
+			},
+
+			Some(MbFunc::Diagnostics) => {
+				println!("Diagnostics");
+				let sub_function = BigEndian::read_u16(&query[2..4]);
+				let data         = BigEndian::read_u16(&query[4..6]);
+				dbg!(sub_function);
+				dbg!(data);
+				self.process_diagnostics(sub_function, data)
+			},
+
+			Some(MbFunc::GetCommEventCounter) => {
+				println!("GetCommEventCounter");
+				Ok(self.comm_event_counter())
+			},
+
+			Some(MbFunc::GetCommEventLog) => {
+				println!("GetCommEventLog");
+				Ok(self.comm_event_log())
+			},
+
+			Some(MbFunc::ReportServerId) => {
+				println!("ReportServerId");
+				Ok(self.report_server_id())
+			},
+
+			Some(MbFunc::ReadFileRecord) => {
+				println!("ReadFileRecord");
+				let byte_count = query[2] as usize;
+				dbg!(byte_count);
+				if !(0x07..=0xF5).contains(&byte_count) || byte_count % FILE_SUB_REQUEST_LEN != 0 {
+					return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_BYTE_COUNT.into()));
+				}
 
-				self.holding_registers[offset] = value;
-				odat.extend(&(offset as u16).to_be_bytes());
-				odat.extend(&(value as u16).to_be_bytes());
-				return Ok(odat);
+				odat.push(0);
+				for sub_request in query[3..3 + byte_count].chunks(FILE_SUB_REQUEST_LEN) {
+					let (number, range) = self.file_range(sub_request)?;
+					// Код функции, длина данных и подответы должны поместиться в PDU
+					if 1 + odat.len() + 2 + range.len() * 2 > 253 {
+						return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_RESPONSE_TOO_LONG.into()));
+					}
+					odat.push((1 + range.len() * 2) as u8);
+					odat.push(FILE_REFERENCE_TYPE);
+					for value in self.files[&number][range].iter() {
+						odat.extend(&value.to_be_bytes());
+					}
+				}
+				odat[0] = (odat.len() - 1) as u8;
+				Ok(odat)
+			},
+
+			Some(MbFunc::WriteFileRecord) => {
+				println!("WriteFileRecord");
+				let byte_count = query[2] as usize;
+				dbg!(byte_count);
+				if !(0x09..=0xFB).contains(&byte_count) {
+					return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_BYTE_COUNT.into()));
+				}
+
+				// Сначала проверяются все подзапросы, чтобы ошибка в одном из них
+				// не оставила файлы записанными частично
+				let data = &query[3..3 + byte_count];
+				let mut writes = Vec::new();
+				let mut pos = 0;
+				while pos < data.len() {
+					if pos + FILE_SUB_REQUEST_LEN > data.len() {
+						return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_BYTE_COUNT.into()));
+					}
+					let (number, range) = self.file_range(&data[pos..pos + FILE_SUB_REQUEST_LEN])?;
+					let end = pos + FILE_SUB_REQUEST_LEN + range.len() * 2;
+					if end > data.len() {
+						return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_BYTE_COUNT.into()));
+					}
+					let mut records = vec![0u16; range.len()];
+					BigEndian::read_u16_into(&data[pos + FILE_SUB_REQUEST_LEN..end], &mut records);
+					writes.push((number, range, records));
+					pos = end;
+				}
+				for (number, range, records) in writes {
+					self.files.get_mut(&number).unwrap()[range].copy_from_slice(&records);
+				}
+				self.mark_written();
+				odat.extend_from_slice(&query[2..3 + byte_count]);
+				Ok(odat)
 			},
 
 			Some(MbFunc::WriteMultipleCoils) => {
 				println!("WriteMultipleCoils");
-				let offset    = BigEndian::read_u16(&self.query[2..4]) as usize;
-				let quantity  = BigEndian::read_u16(&self.query[4..6]) as usize;
+				let offset    = BigEndian::read_u16(&query[2..4]);
+				let quantity  = BigEndian::read_u16(&query[4..6]) as usize;
 				dbg!(offset);
 				dbg!(quantity);
-				let byte_count = self.query[6] as usize;
+				let byte_count = query[6] as usize;
 				let byte_count_from_quantity = (quantity as f32 / 8_f32).ceil() as usize;
 				
 				if quantity == 0 || quantity > 0x07B0 { return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_QUANTITY.into())); }
 				if byte_count != byte_count_from_quantity { return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_BYTE_COUNT.into())); }
-				if offset + quantity >= N_COILS { return Err(MbExcWithMessage::new(MbExc::IllegalDataAddress, STR_INDEX_OUT.into())); }
 
-				unpack_bits(&self.query[7..7+byte_count], &mut self.coils[offset..offset+quantity]);
-				odat.extend(&(offset as u16).to_be_bytes());
+				let mut bits = vec![0u8; quantity];
+				unpack_bits(&query[7..7+byte_count], &mut bits);
+				self.store.write_bits(BitTable::Coils, offset, &bits)?;
+				self.mark_written();
+				odat.extend(&offset.to_be_bytes());
 				odat.extend(&(quantity as u16).to_be_bytes());
-				return Ok(odat);
+				Ok(odat)
 			},
 			
 			Some(MbFunc::WriteMultipleRegisters) => {
 				println!("WriteMultipleRegisters");
-				let offset    = BigEndian::read_u16(&self.query[2..4]) as usize;
-				let quantity  = BigEndian::read_u16(&self.query[4..6]) as usize;
+				let offset    = BigEndian::read_u16(&query[2..4]);
+				let quantity  = BigEndian::read_u16(&query[4..6]) as usize;
 				dbg!(offset);
 				dbg!(quantity);
-				let byte_count = self.query[6] as usize;
+				let byte_count = query[6] as usize;
 
 				if quantity == 0 || quantity > 0x007B { return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_QUANTITY.into())); }
 				if byte_count != quantity * 2 { return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_BYTE_COUNT.into())); }
-				if offset + quantity >= N_HOLDING_REGISTERS { return Err(MbExcWithMessage::new(MbExc::IllegalDataAddress, STR_INDEX_OUT.into())); }
 				
-				odat.extend(&(offset as u16).to_be_bytes());
+				let mut registers = vec![0u16; quantity];
+				BigEndian::read_u16_into(&query[7..7 + byte_count], &mut registers);
+				self.store.write_registers(RegisterTable::HoldingRegisters, offset, &registers)?;
+				self.mark_written();
+				odat.extend(&offset.to_be_bytes());
 				odat.extend(&(quantity as u16).to_be_bytes());
-				BigEndian::read_u16_into(
-					&self.query[7..7 + byte_count],
-					&mut self.holding_registers[offset..offset + quantity]
-				);
-				return Ok(odat);
+				Ok(odat)
+			},
+
+			Some(MbFunc::MaskWriteRegister) => {
+				println!("MaskWriteRegister");
+				let offset   = BigEndian::read_u16(&query[2..4]);
+				let and_mask = BigEndian::read_u16(&query[4..6]);
+				let or_mask  = BigEndian::read_u16(&query[6..8]);
+				dbg!(offset);
+				dbg!(and_mask);
+				dbg!(or_mask);
+
+				let current = self.store.read_registers(RegisterTable::HoldingRegisters, offset, 1)?[0];
+				let value = (current & and_mask) | (or_mask & !and_mask);
+				self.store.write_registers(RegisterTable::HoldingRegisters, offset, &[value])?;
+				self.mark_written();
+				odat.extend_from_slice(&query[2..8]);
+				Ok(odat)
+			},
+
+			Some(MbFunc::ReadWriteMultipleRegisters) => {
+				println!("ReadWriteMultipleRegisters");
+				let read_offset    = BigEndian::read_u16(&query[2..4]);
+				let read_quantity  = BigEndian::read_u16(&query[4..6]) as usize;
+				let write_offset   = BigEndian::read_u16(&query[6..8]);
+				let write_quantity = BigEndian::read_u16(&query[8..10]) as usize;
+				dbg!(read_offset);
+				dbg!(read_quantity);
+				dbg!(write_offset);
+				dbg!(write_quantity);
+				let byte_count = query[10] as usize;
+
+				if read_quantity == 0 || read_quantity > 0x007D { return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_QUANTITY.into())); }
+				if write_quantity == 0 || write_quantity > 0x0079 { return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_QUANTITY.into())); }
+				if byte_count != write_quantity * 2 { return Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_BYTE_COUNT.into())); }
+
+				// По спецификации запись выполняется раньше чтения. Пробное чтение
+				// до записи, чтобы при неверном адресе чтения регистры не менялись
+				self.store.read_registers(RegisterTable::HoldingRegisters, read_offset, read_quantity)?;
+				let mut registers = vec![0u16; write_quantity];
+				BigEndian::read_u16_into(&query[11..11 + byte_count], &mut registers);
+				self.store.write_registers(RegisterTable::HoldingRegisters, write_offset, &registers)?;
+				self.mark_written();
+
+				let registers = self.store.read_registers(RegisterTable::HoldingRegisters, read_offset, read_quantity)?;
+				odat.push((read_quantity * 2) as u8);
+				let tlen = odat.len();
+				odat.resize(tlen + read_quantity * 2, 0);
+				BigEndian::write_u16_into(&registers, &mut odat[tlen..]);
+				Ok(odat)
+			},
+
+			Some(MbFunc::ReadFifoQueue) => {
+				println!("ReadFifoQueue");
+				let address = BigEndian::read_u16(&query[2..4]);
+				dbg!(address);
+				self.read_fifo_queue(address)
+			},
+
+			Some(MbFunc::EncapsulatedInterfaceTransport) => {
+				println!("EncapsulatedInterfaceTransport");
+				let mei_type  = query[2];
+				let code      = query[3];
+				let object_id = query[4];
+				dbg!(mei_type);
+				dbg!(code);
+				dbg!(object_id);
+				self.identification.read(mei_type, code, object_id)
 			},
 
 			None => Err(MbExcWithMessage::new(MbExc::IllegalFunction, STR_ILLEGAL_FUNCTION.into())),
@@ -174,3 +333,175 @@ impl Server {
 		} // End match
 	} // End fn
 } // End impl
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::server::store::DataStore;
+
+	fn exc(result: Result<Vec<u8>, MbExcWithMessage>) -> MbExc {
+		result.expect_err("ожидалось исключение").exc
+	}
+
+	#[test]
+	fn write_and_read_holding_registers() {
+		let mut d = Device::new();
+		assert_eq!(d.process_function_code(&[1, 0x06, 0x00, 0x05, 0x12, 0x34]).unwrap(), vec![0x00, 0x05, 0x12, 0x34]);
+		assert_eq!(
+			d.process_function_code(&[1, 0x10, 0x00, 0x06, 0x00, 0x02, 0x04, 0xAB, 0xCD, 0x00, 0x01]).unwrap(),
+			vec![0x00, 0x06, 0x00, 0x02]
+		);
+		assert_eq!(
+			d.process_function_code(&[1, 0x03, 0x00, 0x05, 0x00, 0x03]).unwrap(),
+			vec![6, 0x12, 0x34, 0xAB, 0xCD, 0x00, 0x01]
+		);
+	}
+
+	#[test]
+	fn mask_write_register() {
+		let mut d = Device::new();
+		d.process_function_code(&[1, 0x06, 0x00, 0x04, 0x00, 0x12]).unwrap();
+		// Пример из спецификации: 0x12 AND 0xF2 OR 0x25 = 0x17
+		assert_eq!(
+			d.process_function_code(&[1, 0x16, 0x00, 0x04, 0x00, 0xF2, 0x00, 0x25]).unwrap(),
+			vec![0x00, 0x04, 0x00, 0xF2, 0x00, 0x25]
+		);
+		assert_eq!(d.process_function_code(&[1, 0x03, 0x00, 0x04, 0x00, 0x01]).unwrap(), vec![2, 0x00, 0x17]);
+		assert_eq!(exc(d.process_function_code(&[1, 0x16, 0x04, 0x00, 0xFF, 0xFF, 0x00, 0x00])), MbExc::IllegalDataAddress);
+	}
+
+	#[test]
+	fn read_write_multiple_registers() {
+		let mut d = Device::new();
+		d.process_function_code(&[1, 0x06, 0x00, 0x01, 0x11, 0x11]).unwrap();
+		// Запись 0x0002..0x0003, затем чтение 0x0001..0x0003 (включая только что записанные)
+		assert_eq!(
+			d.process_function_code(&[1, 0x17, 0x00, 0x01, 0x00, 0x03, 0x00, 0x02, 0x00, 0x02, 0x04, 0xAA, 0xAA, 0xBB, 0xBB]).unwrap(),
+			vec![6, 0x11, 0x11, 0xAA, 0xAA, 0xBB, 0xBB]
+		);
+		assert_eq!(exc(d.process_function_code(&[1, 0x17, 0x00, 0x00, 0x00, 0x7E, 0x00, 0x00, 0x00, 0x01, 0x02, 0, 0])), MbExc::IllegalDataValue);
+		assert_eq!(exc(d.process_function_code(&[1, 0x17, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0, 0, 0, 0])), MbExc::IllegalDataValue);
+		assert_eq!(exc(d.process_function_code(&[1, 0x17, 0x00, 0x00, 0x00, 0x01, 0x04, 0x00, 0x00, 0x01, 0x02, 0, 0])), MbExc::IllegalDataAddress);
+		// Неверный адрес чтения: запись не выполняется
+		assert_eq!(exc(d.process_function_code(&[1, 0x17, 0x04, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x02, 0x22, 0x22])), MbExc::IllegalDataAddress);
+		assert_eq!(d.process_function_code(&[1, 0x03, 0x00, 0x01, 0x00, 0x01]).unwrap(), vec![2, 0x11, 0x11]);
+	}
+
+	#[test]
+	fn write_and_read_coils() {
+		let mut d = Device::new();
+		assert_eq!(d.process_function_code(&[1, 0x05, 0x00, 0x02, 0xFF, 0x00]).unwrap(), vec![0x00, 0x02, 0xFF, 0x00]);
+		assert_eq!(
+			d.process_function_code(&[1, 0x0F, 0x00, 0x08, 0x00, 0x03, 0x01, 0x05]).unwrap(),
+			vec![0x00, 0x08, 0x00, 0x03]
+		);
+		assert_eq!(d.process_function_code(&[1, 0x01, 0x00, 0x00, 0x00, 0x0B]).unwrap(), vec![2, 0x04, 0x05]);
+	}
+
+	#[test]
+	fn read_inputs() {
+		let mut d = Device::new();
+		d.store_mut().write_bits(BitTable::DiscreteInputs, 1, &[1]).unwrap();
+		d.store_mut().write_registers(RegisterTable::InputRegisters, 2, &[0x0102]).unwrap();
+		assert_eq!(d.process_function_code(&[1, 0x02, 0x00, 0x00, 0x00, 0x02]).unwrap(), vec![1, 0x02]);
+		assert_eq!(d.process_function_code(&[1, 0x04, 0x00, 0x02, 0x00, 0x01]).unwrap(), vec![2, 0x01, 0x02]);
+	}
+
+	#[test]
+	fn writes_change_generation() {
+		let mut d = Device::new();
+		let g = d.generation();
+		d.process_function_code(&[1, 0x03, 0x00, 0x00, 0x00, 0x01]).unwrap();
+		assert_eq!(d.generation(), g);
+		d.process_function_code(&[1, 0x06, 0x00, 0x00, 0x00, 0x01]).unwrap();
+		assert_ne!(d.generation(), g);
+	}
+
+	#[test]
+	fn exceptions() {
+		let mut d = Device::new();
+		assert_eq!(exc(d.process_function_code(&[1, 0x03, 0x00, 0x00, 0x00, 0x00])), MbExc::IllegalDataValue);
+		assert_eq!(exc(d.process_function_code(&[1, 0x03, 0x00, 0x00, 0x00, 0x7E])), MbExc::IllegalDataValue);
+		assert_eq!(exc(d.process_function_code(&[1, 0x03, 0xFF, 0x00, 0x00, 0x01])), MbExc::IllegalDataAddress);
+		assert_eq!(exc(d.process_function_code(&[1, 0x06, 0x04, 0x00, 0x00, 0x01])), MbExc::IllegalDataAddress);
+		assert!(d.process_function_code(&[1, 0x06, 0x03, 0xFF, 0x00, 0x01]).is_ok());
+		assert_eq!(exc(d.process_function_code(&[1, 0x05, 0x00, 0x00, 0x12, 0x34])), MbExc::IllegalDataValue);
+		assert_eq!(exc(d.process_function_code(&[1, 0x0F, 0x00, 0x00, 0x00, 0x09, 0x01, 0x00])), MbExc::IllegalDataValue);
+		assert_eq!(exc(d.process_function_code(&[1, 0x60])), MbExc::IllegalFunction);
+	}
+
+	// Хранилище, вычисляющее значения регистров и запрещающее запись
+	struct Computed;
+
+	impl DataStore for Computed {
+		fn read_bits(&mut self, _table: BitTable, _address: u16, quantity: usize) -> Result<Vec<u8>, MbExcWithMessage> {
+			Ok(vec![1; quantity])
+		}
+		fn write_bits(&mut self, _table: BitTable, _address: u16, _values: &[u8]) -> Result<(), MbExcWithMessage> {
+			Err(MbExcWithMessage::new(MbExc::SlaveDeviceBusy, String::new()))
+		}
+		fn read_registers(&mut self, _table: RegisterTable, address: u16, quantity: usize) -> Result<Vec<u16>, MbExcWithMessage> {
+			Ok((0..quantity as u16).map(|i| address + i).collect())
+		}
+		fn write_registers(&mut self, _table: RegisterTable, _address: u16, _values: &[u16]) -> Result<(), MbExcWithMessage> {
+			Err(MbExcWithMessage::new(MbExc::SlaveDeviceFailure, String::new()))
+		}
+	}
+
+	#[test]
+	fn custom_store() {
+		let mut d = Device::with_store(Box::new(Computed));
+		assert_eq!(d.process_function_code(&[1, 0x04, 0x10, 0x00, 0x00, 0x02]).unwrap(), vec![4, 0x10, 0x00, 0x10, 0x01]);
+		assert_eq!(d.process_function_code(&[1, 0x01, 0x00, 0x00, 0x00, 0x03]).unwrap(), vec![1, 0x07]);
+		assert_eq!(exc(d.process_function_code(&[1, 0x05, 0x00, 0x00, 0xFF, 0x00])), MbExc::SlaveDeviceBusy);
+		assert_eq!(exc(d.process_function_code(&[1, 0x06, 0x00, 0x00, 0x00, 0x01])), MbExc::SlaveDeviceFailure);
+		assert_eq!(d.generation(), 0);
+	}
+
+	#[test]
+	fn read_device_identification() {
+		let mut d = Device::new();
+		d.set_identification(crate::server::identification::DeviceIdentification::new("V", "P", "1"));
+		assert_eq!(
+			d.process_function_code(&[1, 0x2B, 0x0E, 0x01, 0x00]).unwrap(),
+			vec![0x0E, 0x01, 0x81, 0x00, 0x00, 3, 0x00, 1, b'V', 0x01, 1, b'P', 0x02, 1, b'1']
+		);
+		assert_eq!(exc(d.process_function_code(&[1, 0x2B, 0x0E, 0x04, 0x05])), MbExc::IllegalDataAddress);
+	}
+
+	#[test]
+	fn file_records() {
+		let mut d = Device::new();
+		d.add_file(4, 20).unwrap();
+		d.add_file(3, 10).unwrap();
+		let write = [
+			1, 0x15, 0x16,
+			0x06, 0x00, 0x04, 0x00, 0x07, 0x00, 0x03, 0x06, 0xAF, 0x04, 0xBE, 0x10, 0x0D,
+			0x06, 0x00, 0x03, 0x00, 0x09, 0x00, 0x01, 0x12, 0x34,
+		];
+		assert_eq!(d.process_function_code(&write).unwrap(), write[2..].to_vec());
+		assert_eq!(d.file(4).unwrap()[6..11], [0, 0x06AF, 0x04BE, 0x100D, 0]);
+
+		assert_eq!(
+			d.process_function_code(&[
+				1, 0x14, 0x0E,
+				0x06, 0x00, 0x04, 0x00, 0x08, 0x00, 0x02,
+				0x06, 0x00, 0x03, 0x00, 0x09, 0x00, 0x01,
+			]).unwrap(),
+			vec![0x0A, 0x05, 0x06, 0x04, 0xBE, 0x10, 0x0D, 0x03, 0x06, 0x12, 0x34]
+		);
+
+		// Ошибка во втором подзапросе отменяет запись целиком
+		assert_eq!(exc(d.process_function_code(&[
+			1, 0x15, 0x12,
+			0x06, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01, 0xFF, 0xFF,
+			0x06, 0x00, 0x03, 0x00, 0x0A, 0x00, 0x01, 0xFF, 0xFF,
+		])), MbExc::IllegalDataAddress);
+		assert_eq!(d.file(4).unwrap()[0], 0);
+		assert_eq!(exc(d.process_function_code(&[1, 0x14, 0x07, 0x05, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01])), MbExc::IllegalDataAddress);
+		assert_eq!(exc(d.process_function_code(&[1, 0x14, 0x07, 0x06, 0x00, 0x05, 0x00, 0x00, 0x00, 0x01])), MbExc::IllegalDataAddress);
+		assert_eq!(exc(d.process_function_code(&[1, 0x14, 0x06, 0x06, 0x00, 0x04, 0x00, 0x00, 0x00])), MbExc::IllegalDataValue);
+		assert!(d.add_file(0, 1).is_err());
+		assert!(d.add_file(1, 10001).is_err());
+	}
+}
@@ -0,0 +1,141 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Сохранение coils, holding registers и файлов записей между перезапусками.
+// Файл состояния имеет тот же формат, что и файл образа (см. image.rs)
+//------------------------------------------------------------------------------
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use crate::server::device::{ Device, SharedDevice };
+use crate::server::image::{ Image, Block, FileBlock, ImageError };
+use crate::server::store::{ BitTable, RegisterTable };
+use crate::server::formal::MbExcWithMessage;
+
+// Период проверки устройства на наличие новых записей
+pub const STATE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl Device {
+	// Загрузка сохранённого состояния. Отсутствие файла не считается ошибкой
+	pub fn restore_state(&mut self, path: &Path) -> Result<bool, ImageError> {
+		if !path.exists() { return Ok(false); }
+		self.load_image(path)?;
+		Ok(true)
+	}
+
+	// Снимок записываемых таблиц в формате образа.
+	// Сохраняются диапазоны, о которых сообщает хранилище
+	pub fn state_image(&mut self) -> Result<Image, MbExcWithMessage> {
+		let mut image = Image::default();
+		for (address, count) in self.store.bit_ranges(BitTable::Coils) {
+			let values = self.store.read_bits(BitTable::Coils, address, count)?;
+			image.coils.push(state_block(address, values.iter().map(|&v| v as i64).collect()));
+		}
+		for (address, count) in self.store.register_ranges(RegisterTable::HoldingRegisters) {
+			let values = self.store.read_registers(RegisterTable::HoldingRegisters, address, count)?;
+			image.holding_registers.push(state_block(address, values.iter().map(|&v| v as i64).collect()));
+		}
+		for (&number, records) in self.files.iter() {
+			image.file.push(FileBlock { number, size: Some(records.len()), records: records.clone() });
+		}
+		Ok(image)
+	}
+}
+
+fn state_block(address: u16, values: Vec<i64>) -> Block {
+	Block {
+		address: address as i64,
+		values:  Some(values),
+		value:   None,
+		count:   None,
+	}
+}
+
+// Атомарная запись образа: сначала во временный файл рядом с целевым,
+// затем переименование поверх старого
+pub fn save_image(image: &Image, path: &Path) -> io::Result<()> {
+	let text = toml::to_string(image).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+	let mut tmp_path = OsString::from(path.as_os_str());
+	tmp_path.push(".tmp");
+	let tmp_path = PathBuf::from(tmp_path);
+	{
+		let mut f = fs::File::create(&tmp_path)?;
+		f.write_all(text.as_bytes())?;
+		f.sync_all()?;
+	}
+	fs::rename(&tmp_path, path)
+}
+
+// Фоновое сохранение состояния. Файл записывается, когда после последней
+// записи через Modbus прошло не меньше debounce, так что серия записей
+// приводит к одному сохранению
+pub fn spawn_state_writer(device: SharedDevice, path: PathBuf, debounce: Duration) -> thread::JoinHandle<()> {
+	thread::spawn(move || {
+		let mut saved = device.lock().unwrap().generation();
+		let mut seen = saved;
+		let mut changed_at = Instant::now();
+		loop {
+			thread::sleep(STATE_POLL_INTERVAL);
+			let generation = device.lock().unwrap().generation();
+			if generation != seen {
+				seen = generation;
+				changed_at = Instant::now();
+				continue;
+			}
+			if seen == saved || changed_at.elapsed() < debounce { continue; }
+
+			let (image, generation) = {
+				let mut d = device.lock().unwrap();
+				(d.state_image(), d.generation())
+			};
+			let result = match image {
+				Ok(image) => save_image(&image, &path),
+				Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.message)),
+			};
+			match result {
+				Ok(()) => {
+					println!("Состояние сохранено в \"{}\"", path.display());
+					saved = generation;
+					seen = generation;
+				},
+				Err(e) => eprintln!("Не удалось сохранить состояние в \"{}\": {}", path.display(), e),
+			}
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn save_and_restore() {
+		let path = std::env::temp_dir().join(format!("modbus_uart_state_{}.toml", std::process::id()));
+		let mut d = Device::new();
+		assert!(!d.restore_state(&path).unwrap());
+		d.process_function_code(&[1, 0x06, 0x00, 0x03, 0xBE, 0xEF]).unwrap();
+		d.process_function_code(&[1, 0x05, 0x03, 0xFF, 0xFF, 0x00]).unwrap();
+		d.add_file(2, 3).unwrap();
+		d.process_function_code(&[1, 0x15, 0x09, 0x06, 0x00, 0x02, 0x00, 0x01, 0x00, 0x01, 0xAB, 0xCD]).unwrap();
+		save_image(&d.state_image().unwrap(), &path).unwrap();
+
+		let mut restored = Device::new();
+		assert!(restored.restore_state(&path).unwrap());
+		fs::remove_file(&path).unwrap();
+		assert_eq!(
+			restored.store_mut().read_registers(RegisterTable::HoldingRegisters, 0, 4).unwrap(),
+			vec![0, 0, 0, 0xBEEF]
+		);
+		assert_eq!(restored.store_mut().read_bits(BitTable::Coils, 0x3FE, 2).unwrap(), vec![0, 1]);
+		assert_eq!(restored.file(2).unwrap(), &[0, 0xABCD, 0]);
+	}
+}
@@ -0,0 +1,111 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Состояние устройства: Report Server ID (0x11) и Read Exception Status (0x07)
+//------------------------------------------------------------------------------
+use crate::server::device::Device;
+use crate::server::store::BitTable;
+use crate::server::formal::*;
+
+// Наибольшая длина идентификатора: функция, счётчик байт
+// и индикатор работы должны поместиться в PDU
+pub const MAX_SERVER_ID_LEN: usize = 253 - 3;
+
+// Количество бит состояния исключений
+pub const N_EXCEPTION_STATUS_BITS: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerId {
+	id:            Vec<u8>,
+	// Индикатор работы: true - устройство в режиме работы (ON)
+	pub run:       bool,
+}
+
+impl ServerId {
+	pub fn new(id: &[u8], run: bool) -> Result<ServerId, String> {
+		if id.len() > MAX_SERVER_ID_LEN {
+			return Err(format!("идентификатор длиннее {} байт", MAX_SERVER_ID_LEN));
+		}
+		Ok(ServerId { id: id.to_vec(), run })
+	}
+
+	pub fn id(&self) -> &[u8] {
+		&self.id
+	}
+}
+
+impl Default for ServerId {
+	fn default() -> ServerId {
+		ServerId { id: env!("CARGO_PKG_NAME").as_bytes().to_vec(), run: true }
+	}
+}
+
+impl Device {
+	pub fn server_id(&self) -> &ServerId {
+		&self.server_id
+	}
+
+	pub fn set_server_id(&mut self, server_id: ServerId) {
+		self.server_id = server_id;
+	}
+
+	// Адреса coils, из которых читаются биты 0 - 7 состояния исключений.
+	// Биты без адреса всегда равны нулю
+	pub fn exception_coils(&self) -> &[Option<u16>; N_EXCEPTION_STATUS_BITS] {
+		&self.exception_coils
+	}
+
+	pub fn set_exception_coils(&mut self, coils: [Option<u16>; N_EXCEPTION_STATUS_BITS]) {
+		self.exception_coils = coils;
+	}
+
+	// Ответ 0x11: счётчик байт, идентификатор, индикатор работы
+	pub(super) fn report_server_id(&self) -> Vec<u8> {
+		let mut odat = Vec::with_capacity(2 + self.server_id.id.len());
+		odat.push((self.server_id.id.len() + 1) as u8);
+		odat.extend_from_slice(&self.server_id.id);
+		odat.push(if self.server_id.run { 0xFF } else { 0x00 });
+		odat
+	}
+
+	// Ответ 0x07: байт состояния, собранный из coils
+	pub(super) fn read_exception_status(&mut self) -> Result<Vec<u8>, MbExcWithMessage> {
+		let mut status = 0u8;
+		for (bit, coil) in self.exception_coils.iter().enumerate() {
+			if let Some(address) = coil {
+				if self.store.read_bits(BitTable::Coils, *address, 1)?[0] != 0 {
+					status |= 1 << bit;
+				}
+			}
+		}
+		Ok(vec![status])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn report_server_id() {
+		let mut d = Device::new();
+		d.set_server_id(ServerId::new(b"PX", false).unwrap());
+		assert_eq!(d.process_function_code(&[1, 0x11]).unwrap(), vec![3, b'P', b'X', 0x00]);
+		assert!(ServerId::new(&[0; MAX_SERVER_ID_LEN + 1], true).is_err());
+	}
+
+	#[test]
+	fn read_exception_status() {
+		let mut d = Device::new();
+		assert_eq!(d.process_function_code(&[1, 0x07]).unwrap(), vec![0x00]);
+		d.set_exception_coils([Some(10), None, Some(11), None, None, None, None, Some(12)]);
+		d.store_mut().write_bits(BitTable::Coils, 10, &[1, 1, 1]).unwrap();
+		assert_eq!(d.process_function_code(&[1, 0x07]).unwrap(), vec![0x85]);
+		d.set_exception_coils([Some(5000), None, None, None, None, None, None, None]);
+		assert_eq!(d.process_function_code(&[1, 0x07]).unwrap_err().exc, MbExc::IllegalDataAddress);
+	}
+}
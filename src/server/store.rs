@@ -0,0 +1,354 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Хранилище таблиц Modbus
+//------------------------------------------------------------------------------
+use std::str::FromStr;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::server::{ N_DISCRETE_INPUTS, N_COILS, N_INPUT_REGISTERS, N_HOLDING_REGISTERS };
+use crate::server::formal::*;
+
+// Размер адресного пространства каждой таблицы Modbus
+pub const ADDRESS_SPACE: usize = 0x10000;
+
+// Битовые таблицы. Значение бита хранится в отдельном байте (0 или 1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitTable {
+	DiscreteInputs,
+	Coils,
+}
+
+// Таблицы 16-битных регистров
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterTable {
+	InputRegisters,
+	HoldingRegisters,
+}
+
+// Источник данных для четырёх таблиц Modbus.
+// Ошибки возвращаются в виде исключений Modbus и передаются мастеру как есть.
+// Запись в discrete inputs и input registers через Modbus невозможна,
+// но хранилище должно её поддерживать для загрузки начального образа
+pub trait DataStore: Send {
+	fn read_bits(&mut self, table: BitTable, address: u16, quantity: usize) -> Result<Vec<u8>, MbExcWithMessage>;
+	fn write_bits(&mut self, table: BitTable, address: u16, values: &[u8]) -> Result<(), MbExcWithMessage>;
+	fn read_registers(&mut self, table: RegisterTable, address: u16, quantity: usize) -> Result<Vec<u16>, MbExcWithMessage>;
+	fn write_registers(&mut self, table: RegisterTable, address: u16, values: &[u16]) -> Result<(), MbExcWithMessage>;
+
+	// Диапазоны адресов (начало, количество), которые хранилище содержит.
+	// Используются для сохранения состояния; пустой список - таблица не сохраняется
+	fn bit_ranges(&self, _table: BitTable) -> Vec<(u16, usize)> { Vec::new() }
+	fn register_ranges(&self, _table: RegisterTable) -> Vec<(u16, usize)> { Vec::new() }
+}
+
+// Размещение таблицы в адресном пространстве: начальный адрес и количество ячеек
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TableLayout {
+	#[serde(default)]
+	pub start: u16,
+	pub size:  usize,
+}
+
+impl TableLayout {
+	pub fn new(start: u16, size: usize) -> Result<TableLayout, String> {
+		let layout = TableLayout { start, size };
+		layout.validate()?;
+		Ok(layout)
+	}
+
+	// Таблица должна помещаться в 16-битное адресное пространство
+	pub fn validate(&self) -> Result<(), String> {
+		if self.start as usize + self.size > ADDRESS_SPACE {
+			return Err(format!("таблица с адресом {} и размером {} выходит за пределы 16-битного адресного пространства", self.start, self.size));
+		}
+		Ok(())
+	}
+}
+
+// Формат: "SIZE" или "START:SIZE"
+impl FromStr for TableLayout {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<TableLayout, String> {
+		let (start, size) = match s.find(':') {
+			Some(i) => (&s[..i], &s[i + 1..]),
+			None    => ("0", s),
+		};
+		let start = start.trim().parse::<u16>().map_err(|e| format!("неверный начальный адрес \"{}\": {}", start, e))?;
+		let size = size.trim().parse::<usize>().map_err(|e| format!("неверный размер \"{}\": {}", size, e))?;
+		TableLayout::new(start, size)
+	}
+}
+
+// Карта таблицы: набор определённых блоков адресов.
+// Обращение к адресу вне блоков приводит к исключению IllegalDataAddress
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableMap {
+	blocks: Vec<TableLayout>,
+}
+
+impl TableMap {
+	// Блоки сортируются, смежные блоки объединяются.
+	// Пересекающиеся блоки считаются ошибкой
+	pub fn new(blocks: &[TableLayout]) -> Result<TableMap, String> {
+		let mut sorted: Vec<TableLayout> = blocks.iter().copied().filter(|b| b.size > 0).collect();
+		sorted.sort_by_key(|b| b.start);
+		let mut merged: Vec<TableLayout> = Vec::with_capacity(sorted.len());
+		for b in sorted {
+			b.validate()?;
+			match merged.last_mut() {
+				Some(last) if (last.start as usize + last.size) > b.start as usize => {
+					return Err(format!("блоки с адресами {} и {} пересекаются", last.start, b.start));
+				},
+				Some(last) if (last.start as usize + last.size) == b.start as usize => last.size += b.size,
+				_ => merged.push(b),
+			}
+		}
+		Ok(TableMap { blocks: merged })
+	}
+
+	// Один блок
+	pub fn single(start: u16, size: usize) -> TableMap {
+		TableMap { blocks: if size > 0 { vec![TableLayout { start, size }] } else { Vec::new() } }
+	}
+
+	pub fn blocks(&self) -> &[TableLayout] {
+		&self.blocks
+	}
+}
+
+// Формат: блоки "[START:]SIZE", разделённые запятыми, например "0:100,1000:50"
+impl FromStr for TableMap {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<TableMap, String> {
+		let blocks = s.split(',').map(|b| b.parse::<TableLayout>()).collect::<Result<Vec<_>, _>>()?;
+		TableMap::new(&blocks)
+	}
+}
+
+// Размещение всех четырёх таблиц
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+	pub discrete_inputs:   TableMap,
+	pub coils:             TableMap,
+	pub input_registers:   TableMap,
+	pub holding_registers: TableMap,
+}
+
+impl Default for Layout {
+	fn default() -> Layout {
+		Layout {
+			discrete_inputs:   TableMap::single(0, N_DISCRETE_INPUTS),
+			coils:             TableMap::single(0, N_COILS),
+			input_registers:   TableMap::single(0, N_INPUT_REGISTERS),
+			holding_registers: TableMap::single(0, N_HOLDING_REGISTERS),
+		}
+	}
+}
+
+// Таблица в памяти: отсортированные непересекающиеся блоки (начальный адрес, данные)
+struct Table<T> {
+	blocks: Vec<(u16, Vec<T>)>,
+}
+
+impl<T: Copy + Default> Table<T> {
+	fn new(map: &TableMap) -> Table<T> {
+		Table {
+			blocks: map.blocks().iter().map(|b| (b.start, vec![T::default(); b.size])).collect(),
+		}
+	}
+
+	// Блок и индексы в нём для диапазона [address, address + quantity).
+	// Диапазон должен целиком лежать внутри одного блока
+	fn locate(&self, address: u16, quantity: usize) -> Result<(usize, std::ops::Range<usize>), MbExcWithMessage> {
+		// Последний блок, начинающийся не позже address
+		let i = match self.blocks.binary_search_by_key(&address, |b| b.0) {
+			Ok(i)  => i,
+			Err(0) => return Err(MbExcWithMessage::new(MbExc::IllegalDataAddress, STR_INDEX_OUT.into())),
+			Err(i) => i - 1,
+		};
+		let (start, data) = &self.blocks[i];
+		let offset = (address - start) as usize;
+		if offset + quantity > data.len() {
+			return Err(MbExcWithMessage::new(MbExc::IllegalDataAddress, STR_INDEX_OUT.into()));
+		}
+		Ok((i, offset..offset + quantity))
+	}
+
+	fn read(&self, address: u16, quantity: usize) -> Result<Vec<T>, MbExcWithMessage> {
+		let (i, r) = self.locate(address, quantity)?;
+		Ok(self.blocks[i].1[r].to_vec())
+	}
+
+	fn write(&mut self, address: u16, values: &[T]) -> Result<(), MbExcWithMessage> {
+		let (i, r) = self.locate(address, values.len())?;
+		self.blocks[i].1[r].copy_from_slice(values);
+		Ok(())
+	}
+
+	fn ranges(&self) -> Vec<(u16, usize)> {
+		self.blocks.iter().map(|(start, data)| (*start, data.len())).collect()
+	}
+}
+
+// Хранилище по умолчанию: таблицы в памяти
+pub struct VecStore {
+	discrete_input:    Table<u8>,
+	coils:             Table<u8>,
+	input_registers:   Table<u16>,
+	holding_registers: Table<u16>,
+}
+
+impl VecStore {
+	// Таблицы заданных размеров, адреса с нуля
+	pub fn new(n_discrete_inputs: usize, n_coils: usize, n_input_registers: usize, n_holding_registers: usize) -> VecStore {
+		VecStore::with_layout(&Layout {
+			discrete_inputs:   TableMap::single(0, n_discrete_inputs),
+			coils:             TableMap::single(0, n_coils),
+			input_registers:   TableMap::single(0, n_input_registers),
+			holding_registers: TableMap::single(0, n_holding_registers),
+		})
+	}
+
+	pub fn with_layout(layout: &Layout) -> VecStore {
+		VecStore {
+			discrete_input:    Table::new(&layout.discrete_inputs),
+			coils:             Table::new(&layout.coils),
+			input_registers:   Table::new(&layout.input_registers),
+			holding_registers: Table::new(&layout.holding_registers),
+		}
+	}
+
+	fn bits(&self, table: BitTable) -> &Table<u8> {
+		match table {
+			BitTable::DiscreteInputs => &self.discrete_input,
+			BitTable::Coils          => &self.coils,
+		}
+	}
+
+	fn bits_mut(&mut self, table: BitTable) -> &mut Table<u8> {
+		match table {
+			BitTable::DiscreteInputs => &mut self.discrete_input,
+			BitTable::Coils          => &mut self.coils,
+		}
+	}
+
+	fn registers(&self, table: RegisterTable) -> &Table<u16> {
+		match table {
+			RegisterTable::InputRegisters   => &self.input_registers,
+			RegisterTable::HoldingRegisters => &self.holding_registers,
+		}
+	}
+
+	fn registers_mut(&mut self, table: RegisterTable) -> &mut Table<u16> {
+		match table {
+			RegisterTable::InputRegisters   => &mut self.input_registers,
+			RegisterTable::HoldingRegisters => &mut self.holding_registers,
+		}
+	}
+}
+
+impl Default for VecStore {
+	fn default() -> VecStore {
+		VecStore::with_layout(&Layout::default())
+	}
+}
+
+impl DataStore for VecStore {
+	fn read_bits(&mut self, table: BitTable, address: u16, quantity: usize) -> Result<Vec<u8>, MbExcWithMessage> {
+		self.bits(table).read(address, quantity)
+	}
+
+	fn write_bits(&mut self, table: BitTable, address: u16, values: &[u8]) -> Result<(), MbExcWithMessage> {
+		self.bits_mut(table).write(address, values)
+	}
+
+	fn read_registers(&mut self, table: RegisterTable, address: u16, quantity: usize) -> Result<Vec<u16>, MbExcWithMessage> {
+		self.registers(table).read(address, quantity)
+	}
+
+	fn write_registers(&mut self, table: RegisterTable, address: u16, values: &[u16]) -> Result<(), MbExcWithMessage> {
+		self.registers_mut(table).write(address, values)
+	}
+
+	fn bit_ranges(&self, table: BitTable) -> Vec<(u16, usize)> {
+		self.bits(table).ranges()
+	}
+
+	fn register_ranges(&self, table: RegisterTable) -> Vec<(u16, usize)> {
+		self.registers(table).ranges()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn vec_store_bounds() {
+		let mut s = VecStore::new(8, 8, 4, 4);
+		s.write_registers(RegisterTable::HoldingRegisters, 2, &[1, 2]).unwrap();
+		assert_eq!(s.read_registers(RegisterTable::HoldingRegisters, 0, 4).unwrap(), vec![0, 0, 1, 2]);
+		assert_eq!(s.read_registers(RegisterTable::InputRegisters, 0, 4).unwrap(), vec![0, 0, 0, 0]);
+		assert_eq!(s.write_registers(RegisterTable::HoldingRegisters, 3, &[1, 2]).unwrap_err().exc, MbExc::IllegalDataAddress);
+		assert_eq!(s.read_bits(BitTable::Coils, 8, 1).unwrap_err().exc, MbExc::IllegalDataAddress);
+		assert_eq!(s.bit_ranges(BitTable::DiscreteInputs), vec![(0, 8)]);
+	}
+
+	#[test]
+	fn layout_with_start_address() {
+		let layout = Layout {
+			holding_registers: "40000:100".parse().unwrap(),
+			coils: "65536".parse().unwrap(),
+			..Layout::default()
+		};
+		let mut s = VecStore::with_layout(&layout);
+		s.write_registers(RegisterTable::HoldingRegisters, 40099, &[7]).unwrap();
+		assert_eq!(s.read_registers(RegisterTable::HoldingRegisters, 40098, 2).unwrap(), vec![0, 7]);
+		assert_eq!(s.read_registers(RegisterTable::HoldingRegisters, 39999, 1).unwrap_err().exc, MbExc::IllegalDataAddress);
+		assert_eq!(s.read_registers(RegisterTable::HoldingRegisters, 40099, 2).unwrap_err().exc, MbExc::IllegalDataAddress);
+		s.write_bits(BitTable::Coils, 0xFFFF, &[1]).unwrap();
+		assert_eq!(s.register_ranges(RegisterTable::HoldingRegisters), vec![(40000, 100)]);
+	}
+
+	#[test]
+	fn parse_table_layout() {
+		assert_eq!("1024".parse::<TableLayout>().unwrap(), TableLayout { start: 0, size: 1024 });
+		assert_eq!("100:16".parse::<TableLayout>().unwrap(), TableLayout { start: 100, size: 16 });
+		assert!("65535:2".parse::<TableLayout>().is_err());
+		assert!("70000:1".parse::<TableLayout>().is_err());
+		assert!("x".parse::<TableLayout>().is_err());
+	}
+
+	#[test]
+	fn sparse_map() {
+		let layout = Layout {
+			holding_registers: "1000:50,0:100,40000:101,100:10".parse().unwrap(),
+			..Layout::default()
+		};
+		assert_eq!(layout.holding_registers.blocks(), &[
+			TableLayout { start: 0, size: 110 },
+			TableLayout { start: 1000, size: 50 },
+			TableLayout { start: 40000, size: 101 },
+		]);
+		let mut s = VecStore::with_layout(&layout);
+		let t = RegisterTable::HoldingRegisters;
+		s.write_registers(t, 105, &[1, 2]).unwrap();
+		assert_eq!(s.read_registers(t, 99, 11).unwrap().len(), 11);
+		assert_eq!(s.read_registers(t, 105, 6).unwrap_err().exc, MbExc::IllegalDataAddress);
+		assert_eq!(s.read_registers(t, 500, 1).unwrap_err().exc, MbExc::IllegalDataAddress);
+		assert_eq!(s.write_registers(t, 1049, &[1, 2]).unwrap_err().exc, MbExc::IllegalDataAddress);
+		s.write_registers(t, 40100, &[3]).unwrap();
+		assert_eq!(s.register_ranges(t), vec![(0, 110), (1000, 50), (40000, 101)]);
+
+		assert!("0:100,50:10".parse::<TableMap>().is_err());
+	}
+}
@@ -0,0 +1,213 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Сервер Modbus TCP (заголовок MBAP), работающий с тем же образом устройства
+//------------------------------------------------------------------------------
+use std::io;
+use std::io::{ Read, Write, ErrorKind };
+use std::net::{ TcpListener, TcpStream, ToSocketAddrs, SocketAddr };
+use std::thread;
+
+use byteorder::{ ByteOrder, BigEndian };
+
+use crate::server::IN_BUF_SIZE;
+use crate::server::device::{ SharedDevice, Units };
+use crate::server::formal::*;
+
+pub const MBAP_HEADER_LEN: usize = 7;
+// Unit id, которым адресуется устройство напрямую (не через шлюз)
+pub const UNIT_ID_DIRECT:  u8 = 0xFF;
+
+pub struct TcpServer {
+	listener: TcpListener,
+	units:    Units,
+}
+
+impl TcpServer {
+	pub fn bind<A: ToSocketAddrs>(addr: A, unit_id: u8, device: SharedDevice) -> io::Result<TcpServer> {
+		let mut units = Units::new();
+		units.insert(unit_id, device);
+		TcpServer::bind_units(addr, units)
+	}
+
+	// Сервер для нескольких устройств, запрос направляется по unit id
+	pub fn bind_units<A: ToSocketAddrs>(addr: A, units: Units) -> io::Result<TcpServer> {
+		Ok(TcpServer {
+			listener: TcpListener::bind(addr)?,
+			units,
+		})
+	}
+
+	// Адрес, на котором фактически открыт сокет (полезно при привязке к порту 0)
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.listener.local_addr()
+	}
+
+	// Приём подключений. Каждый клиент обслуживается в отдельном потоке
+	pub fn start(&self) -> io::Result<()> {
+		for stream in self.listener.incoming() {
+			match stream {
+				Ok(s) => {
+					let units = self.units.clone();
+					thread::spawn(move || {
+						if let Err(e) = handle_client(s, units) {
+							eprintln!("TCP: ошибка соединения, {}", e);
+						}
+					});
+				},
+				Err(e) => eprintln!("TCP: не удалось принять подключение, {}", e),
+			}
+		}
+		Ok(())
+	}
+}
+
+// Обмен с одним клиентом: чтение заголовка MBAP, затем PDU.
+// Ответ отправляется с тем же transaction id
+fn handle_client(mut stream: TcpStream, units: Units) -> io::Result<()> {
+	let peer = stream.peer_addr()?;
+	println!("TCP: клиент {} подключен", peer);
+	let mut header = [0u8; MBAP_HEADER_LEN];
+	let mut query = vec![0u8; IN_BUF_SIZE];
+
+	loop {
+		match stream.read_exact(&mut header) {
+			Ok(()) => {},
+			Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+				println!("TCP: клиент {} отключен", peer);
+				return Ok(());
+			},
+			Err(e) => return Err(e),
+		}
+		let transaction_id = BigEndian::read_u16(&header[0..2]);
+		let protocol_id    = BigEndian::read_u16(&header[2..4]);
+		let length         = BigEndian::read_u16(&header[4..6]) as usize;
+
+		// length включает unit id и PDU, PDU содержит как минимум код функции
+		if protocol_id != 0 || !(2..=MAX_PDU_LEN + 1).contains(&length) {
+			eprintln!("TCP: неверный заголовок MBAP {:02X?}, соединение закрыто", header);
+			return Ok(());
+		}
+		query[0] = header[6];
+		stream.read_exact(&mut query[1..length])?;
+		println!("TCP RX [{:04X}] {:02X?}", transaction_id, &query[..length]);
+
+		let pdu = process_query(&query[..length], &units);
+
+		let mut obuf = Vec::with_capacity(MBAP_HEADER_LEN + pdu.len());
+		obuf.extend_from_slice(&transaction_id.to_be_bytes());
+		obuf.extend_from_slice(&0u16.to_be_bytes());
+		obuf.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+		obuf.push(query[0]);
+		obuf.extend_from_slice(&pdu);
+		println!("TCP TX [{:04X}] {:02X?}", transaction_id, &obuf[MBAP_HEADER_LEN - 1..]);
+		stream.write_all(&obuf)?;
+	}
+}
+
+// Формирование PDU ответа на запрос query (unit id + PDU запроса)
+fn process_query(query: &[u8], units: &Units) -> Vec<u8> {
+	let function = query[1];
+	// Прямое обращение возможно, только если устройство одно
+	let device = match units.get(&query[0]) {
+		None if query[0] == UNIT_ID_DIRECT && units.len() == 1 => units.values().next(),
+		d => d,
+	};
+	let result = if let Some(device) = device {
+		// Длина PDU известна из заголовка, она должна совпадать с ожидаемой для функции
+		let mut device = device.lock().unwrap();
+		match device.query_len(query) {
+			Ok(l) if l == query.len() + 2 => device.process_function_code(query),
+			Ok(_) => Err(MbExcWithMessage::new(MbExc::IllegalDataValue, STR_INVALID_LENGTH.into())),
+			Err(e) => Err(e),
+		}
+	}
+	else {
+		Err(MbExcWithMessage::new(MbExc::GatewayPathUnavailable, "Unit id не совпадает".into()))
+	};
+
+	let mut pdu = Vec::with_capacity(MAX_PDU_LEN);
+	match result {
+		Ok(data) => {
+			pdu.push(function);
+			pdu.extend_from_slice(data.as_slice());
+		},
+		Err(MbExcWithMessage { exc, message, .. }) => {
+			eprintln!("Ошибка: {}", message);
+			pdu.push(function | 0x80);
+			pdu.push(exc as u8);
+		},
+	}
+	pdu
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::server::device::Device;
+
+	fn start_server() -> SocketAddr {
+		let server = TcpServer::bind("127.0.0.1:0", 1, Device::new().shared()).unwrap();
+		let addr = server.local_addr().unwrap();
+		thread::spawn(move || server.start());
+		addr
+	}
+
+	fn transact(stream: &mut TcpStream, transaction_id: u16, unit_id: u8, pdu: &[u8]) -> Vec<u8> {
+		let mut frame = Vec::new();
+		frame.extend_from_slice(&transaction_id.to_be_bytes());
+		frame.extend_from_slice(&[0, 0]);
+		frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+		frame.push(unit_id);
+		frame.extend_from_slice(pdu);
+		stream.write_all(&frame).unwrap();
+
+		let mut header = [0u8; MBAP_HEADER_LEN];
+		stream.read_exact(&mut header).unwrap();
+		assert_eq!(BigEndian::read_u16(&header[0..2]), transaction_id);
+		assert_eq!(BigEndian::read_u16(&header[2..4]), 0);
+		assert_eq!(header[6], unit_id);
+		let mut response = vec![0u8; BigEndian::read_u16(&header[4..6]) as usize - 1];
+		stream.read_exact(&mut response).unwrap();
+		response
+	}
+
+	#[test]
+	fn clients_share_device() {
+		let addr = start_server();
+		let mut a = TcpStream::connect(addr).unwrap();
+		let mut b = TcpStream::connect(addr).unwrap();
+		assert_eq!(transact(&mut a, 0x1234, 1, &[0x06, 0x00, 0x01, 0xAB, 0xCD]), vec![0x06, 0x00, 0x01, 0xAB, 0xCD]);
+		assert_eq!(transact(&mut b, 0x0001, UNIT_ID_DIRECT, &[0x03, 0x00, 0x01, 0x00, 0x01]), vec![0x03, 0x02, 0xAB, 0xCD]);
+		assert_eq!(transact(&mut a, 0x1235, 1, &[0x03, 0x00, 0x01, 0x00, 0x01]), vec![0x03, 0x02, 0xAB, 0xCD]);
+	}
+
+	#[test]
+	fn exception_responses() {
+		let addr = start_server();
+		let mut s = TcpStream::connect(addr).unwrap();
+		assert_eq!(transact(&mut s, 1, 1, &[0x03, 0x00, 0x01]), vec![0x83, MbExc::IllegalDataValue as u8]);
+		assert_eq!(transact(&mut s, 2, 1, &[0x42]), vec![0xC2, MbExc::IllegalFunction as u8]);
+		assert_eq!(transact(&mut s, 3, 7, &[0x03, 0x00, 0x01, 0x00, 0x01]), vec![0x83, MbExc::GatewayPathUnavailable as u8]);
+	}
+
+	#[test]
+	fn multiple_units() {
+		let mut units = Units::new();
+		units.insert(1, Device::new().shared());
+		units.insert(2, Device::new().shared());
+		let server = TcpServer::bind_units("127.0.0.1:0", units).unwrap();
+		let addr = server.local_addr().unwrap();
+		thread::spawn(move || server.start());
+
+		let mut s = TcpStream::connect(addr).unwrap();
+		transact(&mut s, 1, 2, &[0x06, 0x00, 0x00, 0x00, 0x07]);
+		assert_eq!(transact(&mut s, 2, 1, &[0x03, 0x00, 0x00, 0x00, 0x01]), vec![0x03, 0x02, 0x00, 0x00]);
+		assert_eq!(transact(&mut s, 3, 2, &[0x03, 0x00, 0x00, 0x00, 0x01]), vec![0x03, 0x02, 0x00, 0x07]);
+		assert_eq!(transact(&mut s, 4, UNIT_ID_DIRECT, &[0x03, 0x00, 0x00, 0x00, 0x01]), vec![0x83, MbExc::GatewayPathUnavailable as u8]);
+	}
+}
@@ -0,0 +1,144 @@
+//------------------------------------------------------------------------------
+// author:	Nikita Makarevich (aka DDRDmakar)
+// email:	makarevich.98@mail.ru
+// 2021
+// This code is under MIT license (see LICENSE.txt)
+//------------------------------------------------------------------------------
+// Простой сервер Modbus RTU
+// Транспорт: источник и приёмник байтов для цикла обработки кадров
+//------------------------------------------------------------------------------
+use std::collections::VecDeque;
+use std::io;
+use std::io::{ Read, Write, ErrorKind };
+use std::net::TcpStream;
+use std::time::Duration;
+
+use serialport::{ SerialPort, Parity, StopBits };
+
+// Пауза между кадрами RTU при скорости выше 19200 бод
+pub const MIN_FRAME_GAP: Duration = Duration::from_micros(1750);
+
+pub trait Transport {
+	// Чтение доступных байтов.
+	// Err(TimedOut) - в течение таймаута данных не было (пауза на линии),
+	// Ok(0) - транспорт закрыт, дальнейшее чтение невозможно
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+	// Запись кадра целиком
+	fn write_frame(&mut self, frame: &[u8]) -> io::Result<()>;
+
+	fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+
+	// Время передачи одного символа, если транспорт о нём знает
+	fn char_time(&self) -> Option<Duration> { None }
+}
+
+impl Transport for Box<dyn SerialPort> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		Read::read(self, buf)
+	}
+
+	fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+		self.write_all(frame)?;
+		self.flush()
+	}
+
+	fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+		SerialPort::set_timeout(self.as_mut(), timeout)?;
+		Ok(())
+	}
+
+	fn char_time(&self) -> Option<Duration> {
+		let us_per_bit = 1000000f32 / self.baud_rate().ok()? as f32;
+		let n_parity_bits = match self.parity().ok()? {
+			Parity::None => 0,
+			Parity::Odd  => 1,
+			Parity::Even => 1,
+		};
+		let n_stop_bits = match self.stop_bits().ok()? {
+			StopBits::One => 1,
+			StopBits::Two => 2,
+		};
+		let n_bits_per_symbol = 1 + 8 + n_parity_bits + n_stop_bits;
+		let us_per_symbol = us_per_bit * n_bits_per_symbol as f32;
+		Some(Duration::from_micros(us_per_symbol as u64))
+	}
+}
+
+// Кадры RTU поверх TCP-соединения (например, через преобразователь интерфейсов)
+impl Transport for TcpStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match Read::read(self, buf) {
+			// На unix истечение таймаута сокета возвращается как WouldBlock
+			Err(ref e) if e.kind() == ErrorKind::WouldBlock => Err(ErrorKind::TimedOut.into()),
+			r => r,
+		}
+	}
+
+	fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+		self.write_all(frame)
+	}
+
+	fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+		self.set_read_timeout(Some(timeout))
+	}
+}
+
+// Транспорт в памяти для тестов и встраивания.
+// Каждый добавленный кадр отделяется от следующего паузой (Err(TimedOut)),
+// после исчерпания входных данных транспорт считается закрытым
+#[derive(Default)]
+pub struct MemoryTransport {
+	input:  VecDeque<Vec<u8>>,
+	gap:    bool,
+	output: Vec<Vec<u8>>,
+}
+
+impl MemoryTransport {
+	pub fn new() -> MemoryTransport {
+		MemoryTransport::default()
+	}
+
+	pub fn push_frame(&mut self, frame: &[u8]) {
+		self.input.push_back(frame.to_vec());
+	}
+
+	// Кадры, записанные в транспорт
+	pub fn written(&self) -> &[Vec<u8>] {
+		&self.output
+	}
+
+	pub fn take_written(&mut self) -> Vec<Vec<u8>> {
+		std::mem::take(&mut self.output)
+	}
+}
+
+impl Transport for MemoryTransport {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.gap {
+			self.gap = false;
+			return Err(ErrorKind::TimedOut.into());
+		}
+		let frame = match self.input.front_mut() {
+			Some(f) => f,
+			None    => return Ok(0),
+		};
+		let n = buf.len().min(frame.len());
+		buf[..n].copy_from_slice(&frame[..n]);
+		frame.drain(..n);
+		if frame.is_empty() {
+			self.input.pop_front();
+			self.gap = true;
+		}
+		Ok(n)
+	}
+
+	fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+		self.output.push(frame.to_vec());
+		Ok(())
+	}
+
+	fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+		Ok(())
+	}
+}